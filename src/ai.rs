@@ -0,0 +1,156 @@
+use anyhow::Error;
+
+use crate::{
+  game_state::{
+    Command, GameAction, GameConfig, GameOutcome, GameState, PlayerIndex, PlayerToken,
+    TerritoryIndex,
+  },
+  map_gen::generate_hex_map,
+};
+
+/// How many turns `run_match` plays when the config doesn't set its own limit.
+pub const DEFAULT_MATCH_TURNS: u64 = 500;
+
+/// How a player in `run_match` decides on its orders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+  /// `plan_commands`, the same AI the server uses for bots.
+  Heuristic,
+  /// Never gives an order, so every territory just sits and fortifies.
+  Turtle,
+  /// Walks into empty territory, but never attacks anyone. Territories it would have attacked from
+  /// fortify instead, so an earlier attack order doesn't stay in force.
+  Expand,
+}
+
+impl Strategy {
+  pub fn plan(self, state: &GameState, player: PlayerIndex) -> Vec<(TerritoryIndex, Command)> {
+    match self {
+      Strategy::Heuristic => plan_commands(state, player),
+      Strategy::Turtle => vec![],
+      Strategy::Expand => plan_commands(state, player)
+        .into_iter()
+        .map(|(source, command)| {
+          let attacks_enemy = command.attack_target().is_some_and(|target| {
+            match state.territories[target].contents {
+              Some((owner, _)) => !state.are_allied(owner, player),
+              None => false,
+            }
+          });
+          match attacks_enemy {
+            true => (source, Command::Fortify),
+            false => (source, command),
+          }
+        })
+        .collect(),
+    }
+  }
+}
+
+/// Plays a whole game on a generated map, with player `i` following `strategies[i]`, until it's
+/// decided or hits the turn limit. Everything is drawn from `seed`, so the same arguments always
+/// give the same outcome, which makes it handy for measuring win rates across many seeds.
+pub fn run_match(
+  config: GameConfig,
+  seed: u64,
+  strategies: &[Strategy],
+) -> Result<GameOutcome, Error> {
+  let mut state = generate_hex_map(seed, strategies.len());
+  state.config = config;
+  if state.config.max_turns.is_none() {
+    state.config.max_turns = Some(DEFAULT_MATCH_TURNS);
+  }
+  let player_tokens: Vec<PlayerToken> =
+    (0..strategies.len()).map(|player| format!("player-{}", player)).collect();
+  for (player, player_token) in player_tokens.iter().enumerate() {
+    state.player_indices_by_token.insert(player_token.clone(), player);
+  }
+  while state.outcome() == GameOutcome::Ongoing {
+    for (player, strategy) in strategies.iter().enumerate() {
+      if !state.player_states[player].is_alive {
+        continue;
+      }
+      for (territory, command) in strategy.plan(&state, player) {
+        let action = GameAction::SetCommand { territory, command };
+        state.process_action(&player_tokens[player], action)?;
+      }
+    }
+    state.step_time()?;
+  }
+  Ok(state.outcome())
+}
+
+/// Picks a command for every territory the player owns using a few greedy rules:
+/// - Walk into an adjacent empty territory if there is one.
+/// - Otherwise attack the weakest adjacent enemy (never an ally) if we clearly outnumber it.
+/// - Otherwise fortify.
+///
+/// The planner is a pure function of the board, so it never touches the game's RNG.
+pub fn plan_commands(state: &GameState, player: PlayerIndex) -> Vec<(TerritoryIndex, Command)> {
+  let mut plan = vec![];
+  for (terr_index, terr) in state.territories.iter().enumerate() {
+    let units = match terr.contents {
+      Some((owner, units)) if owner == player => units,
+      _ => continue,
+    };
+    let empty_neighbor =
+      terr.adjacent.iter().copied().find(|&adj| state.territories[adj].contents.is_none());
+    let weakest_enemy = terr
+      .adjacent
+      .iter()
+      .filter_map(|&adj| match state.territories[adj].contents {
+        Some((owner, enemy_units)) if !state.are_allied(owner, player) => Some((enemy_units, adj)),
+        _ => None,
+      })
+      .min();
+    let command = match (empty_neighbor, weakest_enemy) {
+      (Some(target), _) => Command::Attack { target },
+      (None, Some((enemy_units, target))) if units > 2 * enemy_units => Command::Attack { target },
+      _ => Command::Fortify,
+    };
+    plan.push((terr_index, command));
+  }
+  plan
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game_state::VictoryCondition;
+
+  #[test]
+  fn expanding_fortifies_instead_of_attacking() {
+    // Player 0's one big stack is surrounded by player 1, so the heuristic would attack.
+    let mut state = generate_hex_map(1, 2);
+    for terr in &mut state.territories {
+      terr.contents = Some((1, 1));
+    }
+    state.territories[0].contents = Some((0, 10));
+    assert!(matches!(
+      Strategy::Heuristic.plan(&state, 0)[..],
+      [(0, Command::Attack { .. })]
+    ));
+    assert_eq!(Strategy::Expand.plan(&state, 0), [(0, Command::Fortify)]);
+  }
+
+  #[test]
+  fn identical_strategies_split_their_wins() {
+    // Two heuristic players rarely break each other, so play for gold to get a decisive result.
+    let config = GameConfig {
+      victory_condition: VictoryCondition::Economic { gold: 30 },
+      ..GameConfig::default()
+    };
+    let mut wins = [0; 2];
+    for seed in 0..200 {
+      let strategies = [Strategy::Heuristic, Strategy::Heuristic];
+      if let GameOutcome::Victory { winner } = run_match(config.clone(), seed, &strategies).unwrap()
+      {
+        wins[winner] += 1;
+      }
+    }
+    let decided = wins[0] + wins[1];
+    assert!(decided >= 20, "only {} games were decided", decided);
+    // Neither seat should win much more than two thirds of the decided games.
+    assert!(wins.iter().all(|&won| 3 * won >= decided), "{:?}", wins);
+  }
+}
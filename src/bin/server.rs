@@ -1,22 +1,838 @@
 use std::{
-  collections::{HashMap, HashSet},
-  sync::{atomic::AtomicBool, Arc},
+  collections::{HashMap, HashSet, VecDeque},
+  convert::Infallible,
+  net::{IpAddr, SocketAddr},
+  ops::RangeInclusive,
+  panic::AssertUnwindSafe,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize},
+    Arc,
+  },
   thread,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Error;
+use anyhow::{anyhow, bail, Context, Error};
 use futures_util::{SinkExt, StreamExt};
-use mapwar::game_state::GameAction;
+use mapwar::{
+  ai,
+  game_state::{
+    generate_player_token, resolve_turn, AnimationEvent, CombatStats, GameAction, GameConfig,
+    GameEvent, GameOutcome, GamePhase, GameState, PlayerIndex, PlayerScore, PlayerToken,
+    PlayerView, TerritoryIndex, TerritoryView,
+  },
+  map_file::MapRegistry,
+  map_gen::MapCache,
+  rng::SeedSource,
+};
 use serde::{Deserialize, Serialize};
 use signal_hook::{consts::SIGTERM, iterator::Signals};
-use tokio::sync::{mpsc, RwLock};
-use warp::{ws, Filter};
+use tokio::sync::{mpsc, Notify, RwLock};
+use warp::{http::StatusCode, ws, Filter};
 
 static IS_SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
 
 type ConnectionId = usize;
+type GameToken = String;
+
+/// Where to look for custom maps when `MAPS_DIR` isn't set.
+const DEFAULT_MAPS_DIR: &str = "maps";
+const DEFAULT_SAVES_DIR: &str = "saves";
+const MIN_TICK_SECONDS: u64 = 1;
+const MAX_TICK_SECONDS: u64 = 60;
+const MIN_PLAYERS: usize = 2;
+const MAX_PLAYERS: usize = 8;
+/// How long a game waits for every seat to fill before starting with however many players it has,
+/// as long as that's at least its minimum.
+const START_GRACE_PERIOD: Duration = Duration::from_secs(30);
+/// How many unfinished games one host may have created at once.
+const MAX_HOSTED_GAMES: usize = 3;
+/// How long a game may wait for enough players to start before it's called off.
+const LOBBY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How long a game that has been won or drawn stays around, so late spectators still get its result.
+const FINISHED_GAME_TTL: Duration = Duration::from_secs(10 * 60);
+const PRACTICE_TICK_SECONDS: u64 = 3;
+/// How many lobby players the matchmaker puts in each game, and how fast those games run.
+const MATCHED_PLAYERS: usize = 2;
+const MATCHED_TICK_SECONDS: u64 = 5;
+/// How long a player may go without an accepted action before the AI starts playing for them,
+/// unless the game's creator picked something else.
+const DEFAULT_IDLE_SECONDS: u64 = 90;
+const MIN_IDLE_SECONDS: u64 = 10;
+const MAX_IDLE_SECONDS: u64 = 3600;
+/// How long a first `Resign` stays armed, waiting for the second one that confirms it.
+const RESIGN_CONFIRMATION_WINDOW: Duration = Duration::from_secs(10);
+const MAX_CHAT_LENGTH: usize = 280;
+/// Each connection may send at most `CHAT_RATE_LIMIT` chat messages per `CHAT_RATE_WINDOW`.
+const CHAT_RATE_LIMIT: usize = 5;
+const CHAT_RATE_WINDOW: Duration = Duration::from_secs(10);
+/// How many `(player, action_id)` pairs each game remembers for deduplicating retried actions.
+const RECENT_ACTION_ID_LIMIT: usize = 256;
+/// How many actions each player may send per turn, so a spamming client can't hog the game lock.
+const MAX_ACTIONS_PER_TURN: u32 = 50;
+/// How many past turns a practice game keeps around for `Rewind`.
+const MAX_SNAPSHOTS: usize = 10;
+/// How many past turns of each player's view we keep to diff against. Clients further behind than
+/// this get a full `GameUpdate` instead of a `StateDelta`.
+const MAX_DELTA_HISTORY: usize = 10;
+/// How many entries the audit log keeps before dropping the oldest.
+const MAX_AUDIT_ENTRIES: usize = 10_000;
+/// How many broadcasts in a row a connection may miss because its channel is full before we give up
+/// on it and disconnect it.
+const MAX_LAG_STRIKES: usize = 5;
+
+/// Returned when a connection tries to act in a game it doesn't hold a seat in.
+#[derive(Debug)]
+struct NotAuthorized;
+
+impl std::fmt::Display for NotAuthorized {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Not authorized to act in this game")
+  }
+}
+
+impl std::error::Error for NotAuthorized {}
+
+/// How fast a new game runs, and how long it waits on idle players.
+#[derive(Clone, Copy)]
+struct GameTiming {
+  tick_seconds: u64,
+  idle_seconds: u64,
+}
+
+/// A game as written to the saves directory after every turn, so it can be picked back up after a
+/// restart. Connections don't survive a restart, so seats aren't saved, and the AI plays for every
+/// human player once they've been idle for the game's idle threshold.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedGame {
+  tick_millis:      u64,
+  idle_millis:      u64,
+  max_players:      usize,
+  min_players:      usize,
+  state:            GameState,
+  bot_players:      HashSet<PlayerIndex>,
+  paused:           bool,
+  started:          bool,
+  allow_rewind:     bool,
+  /// When the next turn was due, in Unix milliseconds.
+  next_tick_at:     u64,
+  combat_stats:     CombatStats,
+  game_events_sent: usize,
+  map_name:         Option<String>,
+}
+
+/// What one pass of `game_tick_loop` came to, for the loop to send out once it's let go of the game.
+struct Tick<'a> {
+  updates:  Vec<(ConnectionId, WebSocketResponse<'a>)>,
+  /// Set once the game has ended, failed or been called off, so the loop should stop.
+  finished: bool,
+}
+
+enum ActionOutcome {
+  Applied,
+  ResignNeedsConfirmation,
+  /// The action carried an `action_id` we've already handled, so it wasn't applied again.
+  Duplicate,
+}
+
+struct Game {
+  tick_interval:     Duration,
+  idle_threshold:    Duration,
+  max_players:       usize,
+  /// Once the grace period is up, the game starts as soon as this many players (bots included)
+  /// are in, rather than waiting for all `max_players`.
+  min_players:       usize,
+  created_at:        Instant,
+  state:             GameState,
+  /// Which player each connection plays as. Clients never name their own player, so this is the
+  /// only thing that lets a connection act in the game.
+  seats:             HashMap<ConnectionId, PlayerToken>,
+  /// Players permanently controlled by the AI.
+  bot_players:       HashSet<PlayerIndex>,
+  /// When each player last had an action accepted, indexed by `PlayerIndex`.
+  last_action_at:    Vec<Instant>,
+  /// When each player sent an unconfirmed `Resign`, indexed by `PlayerIndex`.
+  pending_resign_at: Vec<Option<Instant>>,
+  recent_action_ids: VecDeque<(PlayerIndex, u64)>,
+  /// How many actions each player has sent this turn, indexed by `PlayerIndex`.
+  actions_this_turn: Vec<u32>,
+  /// While paused the tick loop doesn't step the game, though players can still change orders.
+  paused:            bool,
+  /// Games wait for every human seat to fill before the first turn is played.
+  started:           bool,
+  /// Set if a turn failed to resolve, after which the game never ticks or accepts actions again.
+  failed:            bool,
+  /// Only practice games can be rewound, since rewinding a real game would undo other players' turns.
+  allow_rewind:      bool,
+  /// The state at the start of each recent turn, oldest first.
+  snapshots:         VecDeque<GameState>,
+  /// When the tick loop will next resolve a turn. Pausing pushes this back.
+  next_tick_at:      Instant,
+  /// How every fight in this game has gone so far.
+  combat_stats:      CombatStats,
+  /// The views recently sent to each player, oldest first, indexed by `PlayerIndex`.
+  recent_views:      Vec<VecDeque<PlayerView>>,
+  /// The last turn each connection said it has applied, via `AckTurn`.
+  acked_turns:       HashMap<ConnectionId, u64>,
+  /// How many of `state.game_events` have already gone out to players.
+  game_events_sent:  usize,
+  /// The named map the game is played on, or `None` for a generated one.
+  map_name:          Option<String>,
+  /// Connections watching without a seat, and whose fog each one watches through. `None` means
+  /// full vision.
+  spectators:        HashMap<ConnectionId, Option<PlayerIndex>>,
+}
+
+impl Game {
+  fn new(tick_interval: Duration, max_players: usize, state: GameState) -> Self {
+    let last_action_at = vec![Instant::now(); state.player_states.len()];
+    let pending_resign_at = vec![None; state.player_states.len()];
+    let actions_this_turn = vec![0; state.player_states.len()];
+    let recent_views = vec![VecDeque::new(); state.player_states.len()];
+    Self {
+      tick_interval,
+      idle_threshold: Duration::from_secs(DEFAULT_IDLE_SECONDS),
+      max_players,
+      min_players: max_players,
+      created_at: Instant::now(),
+      state,
+      seats: HashMap::new(),
+      bot_players: HashSet::new(),
+      last_action_at,
+      pending_resign_at,
+      recent_action_ids: VecDeque::new(),
+      actions_this_turn,
+      paused: false,
+      started: false,
+      failed: false,
+      allow_rewind: false,
+      snapshots: VecDeque::new(),
+      next_tick_at: Instant::now() + tick_interval,
+      combat_stats: CombatStats::default(),
+      recent_views,
+      acked_turns: HashMap::new(),
+      game_events_sent: 0,
+      map_name: None,
+      spectators: HashMap::new(),
+    }
+  }
+
+  /// Sets up a new game on `state`, which `check_game_options` has already accepted the settings
+  /// for: every player gets a fresh token, and the last `bots` of them are played by the AI.
+  fn configured(
+    mut state: GameState,
+    timing: GameTiming,
+    players: RangeInclusive<usize>,
+    bots: usize,
+  ) -> Self {
+    let (min_players, max_players) = players.into_inner();
+    for player_index in 0..max_players {
+      state.player_indices_by_token.insert(generate_player_token(), player_index);
+    }
+    let mut game = Self::new(Duration::from_secs(timing.tick_seconds), max_players, state);
+    game.idle_threshold = Duration::from_secs(timing.idle_seconds);
+    game.bot_players.extend(max_players - bots..max_players);
+    game.min_players = min_players;
+    // Only practice games have bots in them.
+    game.allow_rewind = bots > 0;
+    game
+  }
+
+  fn to_saved(&self) -> SavedGame {
+    SavedGame {
+      tick_millis:      self.tick_interval.as_millis() as u64,
+      idle_millis:      self.idle_threshold.as_millis() as u64,
+      max_players:      self.max_players,
+      min_players:      self.min_players,
+      state:            self.state.clone(),
+      bot_players:      self.bot_players.clone(),
+      paused:           self.paused,
+      started:          self.started,
+      allow_rewind:     self.allow_rewind,
+      next_tick_at:     unix_millis(self.next_tick_at),
+      combat_stats:     self.combat_stats,
+      game_events_sent: self.game_events_sent,
+      map_name:         self.map_name.clone(),
+    }
+  }
+
+  /// Rebuilds a saved game, due to tick when it was before the restart, or right away if that's
+  /// already passed.
+  fn from_saved(saved: SavedGame) -> Self {
+    let mut game = Self::new(
+      Duration::from_millis(saved.tick_millis),
+      saved.max_players,
+      saved.state,
+    );
+    game.idle_threshold = Duration::from_millis(saved.idle_millis);
+    game.min_players = saved.min_players;
+    game.bot_players = saved.bot_players;
+    game.paused = saved.paused;
+    game.started = saved.started;
+    game.allow_rewind = saved.allow_rewind;
+    game.next_tick_at = instant_from_unix_millis(saved.next_tick_at);
+    game.combat_stats = saved.combat_stats;
+    game.game_events_sent = saved.game_events_sent;
+    game.map_name = saved.map_name;
+    game
+  }
+
+  /// Starts the connection spectating through `player`'s fog, or with full vision if `None`, and
+  /// returns the board as it now sees it. If the game is already over, there's nothing left to
+  /// watch, so it gets the result instead.
+  fn spectate<'a>(
+    &mut self,
+    game_token: &'a str,
+    connection_id: ConnectionId,
+    player: Option<PlayerIndex>,
+  ) -> WebSocketResponse<'a> {
+    let outcome = self.state.outcome();
+    if outcome != GameOutcome::Ongoing {
+      return WebSocketResponse::GameOver {
+        game_token,
+        outcome,
+        scoreboard: self.state.scoreboard(),
+      };
+    }
+    self.spectators.insert(connection_id, player);
+    let view = self.state.player_view(player);
+    self.update(game_token, view, vec![])
+  }
+
+  /// Whether any of `connections` is seated in the game.
+  fn has_seat_among(&self, connections: &HashSet<ConnectionId>) -> bool {
+    self.seats.keys().any(|connection_id| connections.contains(connection_id))
+  }
+
+  /// Which player a connection is seated as, if any.
+  fn seated_player(&self, connection_id: ConnectionId) -> Option<PlayerIndex> {
+    self
+      .seats
+      .get(&connection_id)
+      .and_then(|player_token| self.state.player_indices_by_token.get(player_token))
+      .copied()
+  }
+
+  /// Builds a `GameUpdate` from a view and events already filtered for the recipient.
+  fn update<'a>(
+    &self,
+    game_token: &'a str,
+    view: PlayerView,
+    events: Vec<AnimationEvent>,
+  ) -> WebSocketResponse<'a> {
+    WebSocketResponse::GameUpdate {
+      game_token,
+      view,
+      phase: self.state.phase,
+      events,
+      next_tick_at: unix_millis(self.next_tick_at),
+    }
+  }
+
+  /// Builds the update for a seated connection after a turn: a `StateDelta` against the last turn
+  /// it acknowledged if we still have that view, or a full `GameUpdate` otherwise.
+  fn turn_update_for<'a>(
+    &self,
+    game_token: &'a str,
+    connection_id: ConnectionId,
+    view: PlayerView,
+    events: Vec<AnimationEvent>,
+  ) -> Option<WebSocketResponse<'a>> {
+    let player = self.seated_player(connection_id)?;
+    let acked_view = self.acked_turns.get(&connection_id).and_then(|&acked_turn| {
+      self.recent_views[player].iter().find(|earlier| earlier.turn == acked_turn)
+    });
+    let update = match acked_view {
+      Some(acked_view) => WebSocketResponse::StateDelta {
+        game_token,
+        turn: view.turn,
+        since_turn: acked_view.turn,
+        territories: view.changed_territories(acked_view),
+        scoreboard: self.state.scoreboard(),
+        phase: self.state.phase,
+        events,
+        next_tick_at: unix_millis(self.next_tick_at),
+      },
+      None => self.update(game_token, view, events),
+    };
+    Some(update)
+  }
+
+  /// A full `GameUpdate` of the current board for a seated connection, as its player sees it.
+  fn catch_up<'a>(
+    &self,
+    game_token: &'a str,
+    connection_id: ConnectionId,
+  ) -> Result<WebSocketResponse<'a>, Error> {
+    if !self.seats.contains_key(&connection_id) {
+      return Err(NotAuthorized.into());
+    }
+    let view = self.state.player_view(self.seated_player(connection_id));
+    Ok(self.update(game_token, view, vec![]))
+  }
+
+  /// A full `GameUpdate` of the current board for every seated player and spectator, each as they're
+  /// allowed to see it.
+  fn current_updates<'a>(&self, game_token: &'a str) -> Vec<(ConnectionId, WebSocketResponse<'a>)> {
+    let seated =
+      self.seats.keys().map(|&connection_id| (connection_id, self.seated_player(connection_id)));
+    let spectating =
+      self.spectators.iter().map(|(&connection_id, &perspective)| (connection_id, perspective));
+    seated
+      .chain(spectating)
+      .map(|(connection_id, viewer)| {
+        let view = self.state.player_view(viewer);
+        (connection_id, self.update(game_token, view, vec![]))
+      })
+      .collect()
+  }
+
+  /// Remembers the views just sent out, so later turns can be sent as deltas against them.
+  fn record_views(&mut self, views: Vec<PlayerView>) {
+    for (recent_views, view) in self.recent_views.iter_mut().zip(views) {
+      if recent_views.len() >= MAX_DELTA_HISTORY {
+        recent_views.pop_front();
+      }
+      recent_views.push_back(view);
+    }
+  }
+
+  /// Remembers the current state so a later `rewind` can return to it.
+  fn save_snapshot(&mut self) {
+    if !self.allow_rewind {
+      return;
+    }
+    if self.snapshots.len() >= MAX_SNAPSHOTS {
+      self.snapshots.pop_front();
+    }
+    self.snapshots.push_back(self.state.clone());
+  }
+
+  /// Restores the state from `turns` turns ago, RNG and all. Games that are over can't be rewound,
+  /// since their tick loop has already stopped.
+  fn rewind(&mut self, turns: u64) -> Result<(), Error> {
+    if !self.allow_rewind {
+      bail!("Only practice games can be rewound");
+    }
+    if self.failed || self.is_over() {
+      bail!("Game is already over");
+    }
+    if turns == 0 || turns > self.snapshots.len() as u64 {
+      bail!(
+        "Can only rewind between 1 and {} turns",
+        self.snapshots.len()
+      );
+    }
+    let keep = self.snapshots.len() - turns as usize;
+    self.snapshots.truncate(keep + 1);
+    // `truncate` leaves the snapshot we want at the back.
+    self.state = self.snapshots.pop_back().ok_or_else(|| anyhow!("No snapshot to rewind to"))?;
+    // Turn numbers are about to be reused, so old views can't be diffed against any more.
+    self.recent_views.iter_mut().for_each(VecDeque::clear);
+    self.acked_turns.clear();
+    self.game_events_sent = self.state.game_events.len();
+    Ok(())
+  }
+
+  /// The game's entry in the `/api/games` listing, or `None` if `query` filters it out.
+  fn summary(&self, game_token: &str, query: &GamesQuery) -> Option<GameSummary> {
+    if query.map.is_some() && self.map_name != query.map {
+      return None;
+    }
+    if query.open && (self.started || self.open_seat().is_none()) {
+      return None;
+    }
+    Some(GameSummary {
+      game_token:     game_token.to_string(),
+      map:            self.map_name.clone(),
+      seated_players: self.seats.len(),
+      max_players:    self.max_players,
+      started:        self.started,
+    })
+  }
+
+  /// Returns the lowest-indexed player who is neither a bot nor already seated.
+  fn open_seat(&self) -> Option<(PlayerIndex, PlayerToken)> {
+    let seated: HashSet<&PlayerToken> = self.seats.values().collect();
+    self
+      .state
+      .player_indices_by_token
+      .iter()
+      .filter(|(player_token, player_index)| {
+        !seated.contains(player_token) && !self.bot_players.contains(player_index)
+      })
+      .map(|(player_token, &player_index)| (player_index, player_token.clone()))
+      .min()
+  }
+
+  /// Seats the connection in the next open slot, and starts the game once every slot is taken.
+  fn seat(&mut self, connection_id: ConnectionId) -> Result<PlayerIndex, Error> {
+    if self.started {
+      bail!("Game already in progress");
+    }
+    if self.seats.contains_key(&connection_id) {
+      bail!("Already seated in this game");
+    }
+    let (player_index, player_token) = match self.open_seat() {
+      Some(seat) => seat,
+      None => bail!("Game is full"),
+    };
+    self.seats.insert(connection_id, player_token);
+    self.spectators.remove(&connection_id);
+    if self.open_seat().is_none() {
+      self.start();
+    }
+    Ok(player_index)
+  }
+
+  /// Whether the game has waited so long for players that it should be called off.
+  fn lobby_expired(&self) -> bool {
+    !self.started && self.created_at.elapsed() >= LOBBY_TIMEOUT
+  }
+
+  /// Whether the game has waited long enough for a full table, and can start with who it has.
+  fn ready_to_start_short(&self) -> bool {
+    !self.started
+      && self.seats.len() + self.bot_players.len() >= self.min_players
+      && self.created_at.elapsed() >= START_GRACE_PERIOD
+  }
+
+  /// Starts the first turn. Any seat nobody claimed is eliminated, so its starting stacks don't sit
+  /// there for the AI to play.
+  fn start(&mut self) {
+    let claimed: HashSet<&PlayerToken> = self.seats.values().collect();
+    let unclaimed: Vec<PlayerIndex> = self
+      .state
+      .player_indices_by_token
+      .iter()
+      .filter(|&(player_token, player_index)| {
+        !claimed.contains(player_token) && !self.bot_players.contains(player_index)
+      })
+      .map(|(_, &player_index)| player_index)
+      .collect();
+    for player_index in unclaimed {
+      // The index came from the game's own token map, so it always names a player.
+      let _ = self.state.eliminate_player(player_index);
+    }
+    self.started = true;
+    self.next_tick_at = Instant::now() + self.tick_interval;
+    // Nobody can be idle before the game has even begun.
+    self.last_action_at.fill(Instant::now());
+  }
+
+  /// Pauses or resumes the game on behalf of a seated player, and returns the `GamePaused` to send
+  /// everyone following the game, so spectators don't keep counting down to a turn that won't come.
+  fn set_paused<'a>(
+    &mut self,
+    game_token: &'a str,
+    connection_id: ConnectionId,
+    paused: bool,
+  ) -> Result<Vec<(ConnectionId, WebSocketResponse<'a>)>, Error> {
+    if !self.seats.contains_key(&connection_id) {
+      return Err(NotAuthorized.into());
+    }
+    if self.is_over() {
+      bail!("Game is already over");
+    }
+    // Time spent paused shouldn't count towards anyone going idle, and the turn gets its full time.
+    if self.paused && !paused {
+      self.last_action_at.fill(Instant::now());
+      self.next_tick_at = Instant::now() + self.tick_interval;
+    }
+    self.paused = paused;
+    let next_tick_at = Some(unix_millis(self.next_tick_at)).filter(|_| !paused);
+    let paused_response = |connection_id| {
+      let response = WebSocketResponse::GamePaused {
+        game_token,
+        paused,
+        next_tick_at,
+      };
+      (connection_id, response)
+    };
+    Ok(self.participants().into_iter().map(paused_response).collect())
+  }
+
+  fn take_action(
+    &mut self,
+    connection_id: ConnectionId,
+    action_id: Option<u64>,
+    action: GameAction,
+  ) -> Result<ActionOutcome, Error> {
+    let player_token = match self.seats.get(&connection_id) {
+      Some(player_token) => player_token.clone(),
+      None => return Err(NotAuthorized.into()),
+    };
+    if self.failed {
+      bail!("Game was stopped after an internal error");
+    }
+    if self.is_over() {
+      bail!("Game is already over");
+    }
+    let player_index = self.state.player_indices_by_token.get(&player_token).copied();
+    // Clients retry actions after a flaky connection, so make sure each one only lands once.
+    let action_key = player_index.zip(action_id);
+    if let Some(action_key) = action_key {
+      if self.recent_action_ids.contains(&action_key) {
+        return Ok(ActionOutcome::Duplicate);
+      }
+    }
+    // Only actions that actually land count against the limit, so retries and typos are free.
+    if let Some(player_index) = player_index {
+      if self.actions_this_turn[player_index] >= MAX_ACTIONS_PER_TURN {
+        bail!("Too many actions this turn, slow down");
+      }
+    }
+    let outcome = self.apply_action(&player_token, player_index, action)?;
+    if let Some(player_index) = player_index {
+      self.actions_this_turn[player_index] += 1;
+    }
+    if let Some(action_key) = action_key {
+      if self.recent_action_ids.len() >= RECENT_ACTION_ID_LIMIT {
+        self.recent_action_ids.pop_front();
+      }
+      self.recent_action_ids.push_back(action_key);
+    }
+    Ok(outcome)
+  }
+
+  fn apply_action(
+    &mut self,
+    player_token: &PlayerToken,
+    player_index: Option<PlayerIndex>,
+    action: GameAction,
+  ) -> Result<ActionOutcome, Error> {
+    // A resignation only goes through if it confirms another one sent shortly before.
+    if let (GameAction::Resign, Some(player_index)) = (&action, player_index) {
+      let now = Instant::now();
+      let confirmed = match self.pending_resign_at[player_index] {
+        Some(armed_at) => now.duration_since(armed_at) <= RESIGN_CONFIRMATION_WINDOW,
+        None => false,
+      };
+      if !confirmed && self.state.player_states[player_index].is_alive {
+        self.pending_resign_at[player_index] = Some(now);
+        return Ok(ActionOutcome::ResignNeedsConfirmation);
+      }
+      self.pending_resign_at[player_index] = None;
+    }
+    self.state.process_action(player_token, action)?;
+    if let Some(player_index) = player_index {
+      self.last_action_at[player_index] = Instant::now();
+    }
+    Ok(ActionOutcome::Applied)
+  }
+
+  /// Lets the AI choose this turn's commands for the bots, and for any living player who has gone
+  /// idle. An idle player's slot is untouched, so they take back control simply by acting again.
+  fn ai_actions(&self) -> Vec<(PlayerToken, GameAction)> {
+    let now = Instant::now();
+    let mut actions = vec![];
+    for (player_token, &player_index) in &self.state.player_indices_by_token {
+      let is_idle = now.duration_since(self.last_action_at[player_index]) >= self.idle_threshold;
+      if !self.state.player_states[player_index].is_alive
+        || !(is_idle || self.bot_players.contains(&player_index))
+      {
+        continue;
+      }
+      for (territory, command) in ai::plan_commands(&self.state, player_index) {
+        actions.push((
+          player_token.clone(),
+          GameAction::SetCommand { territory, command },
+        ));
+      }
+    }
+    actions
+  }
+
+  /// Whether the game has been won or drawn. Finished games are kept around for a while after their
+  /// tick loop stops, but nothing can be done in them any more.
+  fn is_over(&self) -> bool {
+    self.state.outcome() != GameOutcome::Ongoing
+  }
+
+  /// Everyone who gets told about the game: seated players, then spectators.
+  fn participants(&self) -> Vec<ConnectionId> {
+    self.seats.keys().chain(self.spectators.keys()).copied().collect()
+  }
+
+  /// Does whatever is due once `next_tick_at` comes around: starting the game short-handed, calling
+  /// it off, or resolving a turn. Does nothing if the deadline was pushed back in the meantime, and
+  /// only pushes it back if the game is paused or still waiting for players.
+  fn tick<'a>(&mut self, game_token: &'a str) -> Tick<'a> {
+    let mut tick = Tick {
+      updates:  vec![],
+      finished: false,
+    };
+    // Resuming a paused game pushes the deadline back while the loop was asleep.
+    if self.next_tick_at > Instant::now() {
+      return tick;
+    }
+    if self.ready_to_start_short() {
+      self.start();
+      let starting = |connection_id| {
+        (
+          connection_id,
+          WebSocketResponse::GameStarting { game_token },
+        )
+      };
+      tick.updates = self.seats.keys().copied().map(starting).collect();
+      return tick;
+    }
+    if self.lobby_expired() {
+      println!(
+        "Calling off game {} after waiting too long for players",
+        game_token
+      );
+      let expired = |connection_id| (connection_id, WebSocketResponse::GameExpired { game_token });
+      tick.updates = self.participants().into_iter().map(expired).collect();
+      tick.finished = true;
+      return tick;
+    }
+    if !self.started || self.paused {
+      self.next_tick_at = Instant::now() + self.tick_interval;
+      return tick;
+    }
+    let ai_actions = self.ai_actions();
+    self.save_snapshot();
+    // A panic mid-turn would otherwise kill the tick loop and leave the game frozen half-resolved,
+    // with clients waiting forever. Whatever state it left behind is never stepped again.
+    let resolved = std::panic::catch_unwind(AssertUnwindSafe(|| {
+      resolve_turn(&mut self.state, &ai_actions)
+    }))
+    .unwrap_or_else(|_| Err(anyhow!("Panicked while resolving the turn")));
+    let turn = match resolved {
+      Ok(turn) => turn,
+      Err(err) => {
+        println!("Stopping game after failed step: {}", err);
+        self.failed = true;
+        let aborted =
+          |connection_id| (connection_id, WebSocketResponse::GameAborted { game_token });
+        tick.updates = self.participants().into_iter().map(aborted).collect();
+        tick.finished = true;
+        return tick;
+      }
+    };
+    for rejected in &turn.rejected {
+      println!("AI order rejected: {}", rejected);
+    }
+    self.actions_this_turn.fill(0);
+    self.combat_stats.add(&turn.step.combat_stats);
+    self.next_tick_at = Instant::now() + self.tick_interval;
+    for warning in &turn.step.warnings {
+      println!("Warning while stepping game: {}", warning);
+    }
+    if turn.step.outcome != GameOutcome::Ongoing {
+      println!("Game over: {:?}", turn.step.outcome);
+      let scoreboard = self.state.scoreboard();
+      for connection_id in self.participants() {
+        let game_over = WebSocketResponse::GameOver {
+          game_token,
+          outcome: turn.step.outcome.clone(),
+          scoreboard: scoreboard.clone(),
+        };
+        tick.updates.push((connection_id, game_over));
+      }
+      tick.finished = true;
+      return tick;
+    }
+    let new_game_events = self.state.game_events[self.game_events_sent..].to_vec();
+    for &connection_id in self.seats.keys() {
+      let player = match self.seated_player(connection_id) {
+        Some(player) => player,
+        None => continue,
+      };
+      let view = turn.views[player].clone();
+      let events = turn.events[player].clone();
+      if let Some(update) = self.turn_update_for(game_token, connection_id, view, events) {
+        tick.updates.push((connection_id, update));
+      }
+      let events = self.state.filter_game_events_for(&new_game_events, player);
+      if !events.is_empty() {
+        tick.updates.push((
+          connection_id,
+          WebSocketResponse::GameEvents { game_token, events },
+        ));
+      }
+    }
+    for (&connection_id, &perspective) in &self.spectators {
+      let (view, events, game_events) = match perspective {
+        Some(player) => (
+          turn.views[player].clone(),
+          turn.events[player].clone(),
+          self.state.filter_game_events_for(&new_game_events, player),
+        ),
+        None => (
+          self.state.player_view(None),
+          turn.step.animation_events.clone(),
+          new_game_events.clone(),
+        ),
+      };
+      tick.updates.push((connection_id, self.update(game_token, view, events)));
+      if !game_events.is_empty() {
+        tick.updates.push((
+          connection_id,
+          WebSocketResponse::GameEvents {
+            game_token,
+            events: game_events,
+          },
+        ));
+      }
+    }
+    self.game_events_sent = self.state.game_events.len();
+    self.record_views(turn.views);
+    tick
+  }
+}
+
+#[derive(Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+enum MapChoice {
+  /// A fresh procedurally generated hex map.
+  Generated,
+  /// One of the maps loaded from the maps directory at startup.
+  Named { name: String },
+}
+
+/// Query parameters for `/api/games`.
+#[derive(Deserialize)]
+struct GamesQuery {
+  /// Only list games on this named map.
+  map:  Option<String>,
+  /// Only list games that haven't started and still have a seat free.
+  #[serde(default)]
+  open: bool,
+}
 
-struct Game {}
+/// One entry in the `/api/games` listing.
+#[derive(Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+struct GameSummary {
+  game_token:     GameToken,
+  /// `None` for a generated map.
+  map:            Option<String>,
+  seated_players: usize,
+  max_players:    usize,
+  started:        bool,
+}
+
+/// One action a connection sent, as kept in the audit log for moderation. Player tokens are never
+/// recorded, since the connection already identifies who sent it.
+#[derive(Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+struct AuditEntry {
+  /// When the action was handled, in Unix milliseconds.
+  at:            u64,
+  connection_id: ConnectionId,
+  game_token:    GameToken,
+  action_kind:   &'static str,
+  /// Why the action was rejected, or `None` if it was accepted.
+  error:         Option<String>,
+}
 
 #[derive(Deserialize, ts_rs::TS)]
 #[serde(rename_all = "camelCase", tag = "kind")]
@@ -25,10 +841,77 @@ enum WebSocketRequest<'a> {
   Ping,
   JoinLobby,
   LeaveLobby,
+  CreateGame {
+    map:          MapChoice,
+    /// Defaults to `SeedSource::Random`.
+    seed:         Option<SeedSource>,
+    tick_seconds: u64,
+    /// How long a player may go without acting before the AI plays for them. Defaults to
+    /// `DEFAULT_IDLE_SECONDS`.
+    idle_seconds: Option<u64>,
+    max_players:  usize,
+    /// If set, the game may start with as few as this many players once `START_GRACE_PERIOD` has
+    /// passed. Defaults to `max_players`, so the game waits for a full table.
+    min_players:  Option<usize>,
+  },
   TakeAction {
+    game_token: &'a str,
+    /// Optional client-chosen id, so a retried action isn't applied twice.
+    action_id:  Option<u64>,
+    action:     GameAction,
+  },
+  /// Checks whether an action would be accepted right now, without applying it.
+  ValidateAction {
     game_token: &'a str,
     action:     GameAction,
   },
+  ChatMessage {
+    text: String,
+  },
+  StartPracticeGame {
+    bots: usize,
+    /// Defaults to `SeedSource::Random`.
+    seed: Option<SeedSource>,
+  },
+  PauseGame {
+    game_token: &'a str,
+  },
+  ResumeGame {
+    game_token: &'a str,
+  },
+  /// Takes an open seat in a specific game that hasn't started yet.
+  JoinGame {
+    game_token: &'a str,
+  },
+  /// Watches a game without a seat, either with full vision (`player: None`) or through one
+  /// player's fog. Sending it again switches perspective. Operators can pass the server's
+  /// `ADMIN_TOKEN` to watch games they're seated in too.
+  SpectateAs {
+    game_token:  &'a str,
+    player:      Option<PlayerIndex>,
+    admin_token: Option<&'a str>,
+  },
+  /// Tells the server which turn the client has applied, so later turns can be sent as deltas.
+  AckTurn {
+    game_token: &'a str,
+    turn:       u64,
+  },
+  /// Practice games only: goes back to the start of an earlier turn.
+  Rewind {
+    game_token: &'a str,
+    turns:      u64,
+  },
+  /// Operator-only: removes a player from a game. Requires the server's `ADMIN_TOKEN`.
+  AdminEliminatePlayer {
+    admin_token:  &'a str,
+    game_token:   &'a str,
+    player_index: PlayerIndex,
+  },
+  /// Operator-only: reports a game's combat telemetry. Requires the server's `ADMIN_TOKEN`.
+  AdminGameStats {
+    admin_token: &'a str,
+    game_token:  &'a str,
+  },
 }
 
 #[derive(Serialize, ts_rs::TS)]
@@ -36,30 +919,154 @@ enum WebSocketRequest<'a> {
 #[ts(export)]
 enum WebSocketResponse<'a> {
   Pong,
-  GameStarting { game_token: &'a str },
+  Error {
+    message: String,
+  },
+  ConfirmResign {
+    window_seconds: u64,
+  },
+  ActionAck {
+    action_id: u64,
+  },
+  /// The answer to `ValidateAction`: `error` is why the action would be rejected, or `None` if it
+  /// would be accepted.
+  ActionValidation {
+    game_token: &'a str,
+    error:      Option<String>,
+  },
+  NotAuthorized {
+    game_token: &'a str,
+  },
+  GameCreated {
+    game_token: &'a str,
+  },
+  GameStarting {
+    game_token: &'a str,
+  },
+  Chat {
+    from: ConnectionId,
+    text: String,
+  },
+  /// Sent to everyone seated in or watching the game whenever it's paused or resumed.
+  GamePaused {
+    game_token:   &'a str,
+    paused:       bool,
+    /// When the next turn resolves, in Unix milliseconds, or `None` while paused.
+    next_tick_at: Option<u64>,
+  },
+  /// Sent to each seated player after every turn, showing the board as they're allowed to see it.
+  GameUpdate {
+    game_token:   &'a str,
+    view:         PlayerView,
+    phase:        GamePhase,
+    events:       Vec<AnimationEvent>,
+    /// When the next turn resolves, in Unix milliseconds.
+    next_tick_at: u64,
+  },
+  /// Like `GameUpdate`, but only with the territories that changed since `since_turn`, the last
+  /// turn the client acknowledged, and the scoreboard in place of every player's full state.
+  StateDelta {
+    game_token:   &'a str,
+    turn:         u64,
+    since_turn:   u64,
+    territories:  Vec<(TerritoryIndex, TerritoryView)>,
+    scoreboard:   Vec<PlayerScore>,
+    phase:        GamePhase,
+    events:       Vec<AnimationEvent>,
+    /// When the next turn resolves, in Unix milliseconds.
+    next_tick_at: u64,
+  },
+  /// The `GameEvent`s from the latest turn that the player is allowed to know about.
+  GameEvents {
+    game_token: &'a str,
+    events:     Vec<GameEvent>,
+  },
+  GameJoined {
+    game_token:   &'a str,
+    player_index: PlayerIndex,
+  },
+  /// Sent to everyone seated in the game once it ends, after which it stops ticking.
+  GameOver {
+    game_token: &'a str,
+    outcome:    GameOutcome,
+    scoreboard: Vec<PlayerScore>,
+  },
+  /// Sent to everyone in the game if a turn fails to resolve, after which it stops ticking. The
+  /// details only go to the server log.
+  GameAborted {
+    game_token: &'a str,
+  },
+  /// Sent to everyone in a game that never got enough players within `LOBBY_TIMEOUT`, after which
+  /// it's gone.
+  GameExpired {
+    game_token: &'a str,
+  },
+  /// Sent to everyone seated in the game when an operator removes a player.
+  PlayerEliminated {
+    game_token:   &'a str,
+    player_index: PlayerIndex,
+  },
+  GameStats {
+    game_token:   &'a str,
+    combat_stats: CombatStats,
+  },
 }
 
 struct ConnectionState {
   connection_id:     ConnectionId,
+  host:              Host,
   wakeup_channel_rx: mpsc::Receiver<ConnectionMessage>,
   wakeup_channel_tx: mpsc::Sender<ConnectionMessage>,
+  /// Fired when broadcasts decide this connection is too far behind to keep.
+  kicked:            Arc<Notify>,
+  /// When this connection sent its most recent chat messages, oldest first.
+  recent_chats:      VecDeque<Instant>,
 }
 
 impl ConnectionState {
-  fn new() -> Self {
+  fn new(remote: Option<SocketAddr>) -> Self {
     let (wakeup_channel_tx, wakeup_channel_rx) = mpsc::channel(8);
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     Self {
-      connection_id: 0,
+      connection_id,
+      host: remote.map_or(Host::Connection(connection_id), |remote| {
+        Host::Address(remote.ip())
+      }),
       wakeup_channel_rx,
       wakeup_channel_tx,
+      kicked: Arc::new(Notify::new()),
+      recent_chats: VecDeque::new(),
+    }
+  }
+
+  fn creator(&self) -> Creator {
+    Creator {
+      connection_id: self.connection_id,
+      host:          Some(self.host),
+    }
+  }
+
+  fn check_chat_rate_limit(&mut self) -> Result<(), Error> {
+    let now = Instant::now();
+    while let Some(&sent_at) = self.recent_chats.front() {
+      if now.duration_since(sent_at) < CHAT_RATE_WINDOW {
+        break;
+      }
+      self.recent_chats.pop_front();
     }
+    if self.recent_chats.len() >= CHAT_RATE_LIMIT {
+      bail!("Too many chat messages, slow down");
+    }
+    self.recent_chats.push_back(now);
+    Ok(())
   }
 
   async fn send_response<'a>(
     tx: &mut futures_util::stream::SplitSink<ws::WebSocket, ws::Message>,
     response: WebSocketResponse<'a>,
   ) -> Result<(), Error> {
-    let message = warp::ws::Message::text(serde_json::to_string(&response).unwrap());
+    let text = serde_json::to_string(&response).context("Couldn't serialize response")?;
+    let message = warp::ws::Message::text(text);
     tx.send(message).await.map_err(|e| e.into())
   }
 
@@ -67,6 +1074,7 @@ impl ConnectionState {
     &mut self,
     text: &str,
     tx: &mut futures_util::stream::SplitSink<ws::WebSocket, ws::Message>,
+    global_state: &'static GlobalState,
   ) -> Result<(), Error> {
     let request: WebSocketRequest = serde_json::from_str(text)?;
     match request {
@@ -75,18 +1083,252 @@ impl ConnectionState {
       }
       WebSocketRequest::JoinLobby => {
         println!("Joining lobby");
+        global_state.main_lobby.write().await.insert(self.connection_id);
       }
       WebSocketRequest::LeaveLobby => {
         println!("Leaving lobby");
+        global_state.leave_lobby(self.connection_id).await;
+      }
+      WebSocketRequest::StartPracticeGame { bots, seed } => {
+        let result = global_state
+          .create_game(
+            self.creator(),
+            MapChoice::Generated,
+            seed.unwrap_or(SeedSource::Random),
+            GameTiming {
+              tick_seconds: PRACTICE_TICK_SECONDS,
+              idle_seconds: DEFAULT_IDLE_SECONDS,
+            },
+            bots.saturating_add(1)..=bots.saturating_add(1),
+            bots,
+          )
+          .await;
+        match result {
+          Ok(game_token) => {
+            let game_token = &game_token;
+            Self::send_response(tx, WebSocketResponse::GameStarting { game_token }).await?;
+          }
+          Err(err) => {
+            let message = err.to_string();
+            Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+          }
+        }
+      }
+      WebSocketRequest::PauseGame { game_token } | WebSocketRequest::ResumeGame { game_token } => {
+        let paused = matches!(request, WebSocketRequest::PauseGame { .. });
+        match global_state.set_paused(self.connection_id, game_token, paused).await {
+          Ok(()) => {}
+          Err(err) if err.downcast_ref::<NotAuthorized>().is_some() => {
+            Self::send_response(tx, WebSocketResponse::NotAuthorized { game_token }).await?;
+          }
+          Err(err) => {
+            let message = err.to_string();
+            Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+          }
+        }
+      }
+      WebSocketRequest::JoinGame { game_token } => {
+        match global_state.join_game(self.connection_id, game_token).await {
+          Ok(player_index) => {
+            Self::send_response(
+              tx,
+              WebSocketResponse::GameJoined {
+                game_token,
+                player_index,
+              },
+            )
+            .await?;
+            // Show the board right away rather than leaving the client blank until the next turn.
+            let response = match global_state.catch_up(self.connection_id, game_token).await {
+              Ok(catch_up) => catch_up,
+              Err(err) => WebSocketResponse::Error {
+                message: err.to_string(),
+              },
+            };
+            Self::send_response(tx, response).await?;
+          }
+          Err(err) => {
+            let message = err.to_string();
+            Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+          }
+        }
+      }
+      WebSocketRequest::SpectateAs {
+        game_token,
+        player,
+        admin_token,
+      } => {
+        let result = global_state
+          .spectate(
+            self.connection_id,
+            self.host,
+            admin_token,
+            game_token,
+            player,
+          )
+          .await;
+        let response = match result {
+          Ok(update) => update,
+          Err(err) => WebSocketResponse::Error {
+            message: err.to_string(),
+          },
+        };
+        Self::send_response(tx, response).await?;
+      }
+      WebSocketRequest::AdminEliminatePlayer {
+        admin_token,
+        game_token,
+        player_index,
+      } => {
+        let result = global_state.eliminate_player(admin_token, game_token, player_index).await;
+        if let Err(err) = result {
+          let message = err.to_string();
+          Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+        }
+      }
+      WebSocketRequest::AdminGameStats {
+        admin_token,
+        game_token,
+      } => {
+        let response = match global_state.combat_stats(admin_token, game_token).await {
+          Ok(combat_stats) => WebSocketResponse::GameStats {
+            game_token,
+            combat_stats,
+          },
+          Err(err) => WebSocketResponse::Error {
+            message: err.to_string(),
+          },
+        };
+        Self::send_response(tx, response).await?;
       }
-      WebSocketRequest::TakeAction { game_token, action } => {
-        println!("Taking action: {:?}", action);
+      WebSocketRequest::AckTurn { game_token, turn } => {
+        match global_state.ack_turn(self.connection_id, game_token, turn).await {
+          Ok(()) => {}
+          Err(err) if err.downcast_ref::<NotAuthorized>().is_some() => {
+            Self::send_response(tx, WebSocketResponse::NotAuthorized { game_token }).await?;
+          }
+          Err(err) => {
+            let message = err.to_string();
+            Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+          }
+        }
+      }
+      WebSocketRequest::Rewind { game_token, turns } => {
+        match global_state.rewind(self.connection_id, game_token, turns).await {
+          Ok(()) => {}
+          Err(err) if err.downcast_ref::<NotAuthorized>().is_some() => {
+            Self::send_response(tx, WebSocketResponse::NotAuthorized { game_token }).await?;
+          }
+          Err(err) => {
+            let message = err.to_string();
+            Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+          }
+        }
+      }
+      WebSocketRequest::ChatMessage { text } => {
+        if let Err(err) = self.send_chat(text, global_state).await {
+          let message = err.to_string();
+          Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+        }
+      }
+      WebSocketRequest::CreateGame {
+        map,
+        seed,
+        tick_seconds,
+        idle_seconds,
+        max_players,
+        min_players,
+      } => {
+        let seed = seed.unwrap_or(SeedSource::Random);
+        let timing = GameTiming {
+          tick_seconds,
+          idle_seconds: idle_seconds.unwrap_or(DEFAULT_IDLE_SECONDS),
+        };
+        let players = min_players.unwrap_or(max_players)..=max_players;
+        let result = global_state.create_game(self.creator(), map, seed, timing, players, 0).await;
+        match result {
+          Ok(game_token) => {
+            Self::send_response(
+              tx,
+              WebSocketResponse::GameCreated {
+                game_token: &game_token,
+              },
+            )
+            .await?;
+          }
+          Err(err) => {
+            let message = err.to_string();
+            Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+          }
+        }
+      }
+      WebSocketRequest::TakeAction {
+        game_token,
+        action_id,
+        action,
+      } => {
+        let result =
+          global_state.take_action(self.connection_id, game_token, action_id, action).await;
+        match result {
+          Ok(ActionOutcome::Applied | ActionOutcome::Duplicate) => {
+            if let Some(action_id) = action_id {
+              Self::send_response(tx, WebSocketResponse::ActionAck { action_id }).await?;
+            }
+          }
+          Ok(ActionOutcome::ResignNeedsConfirmation) => {
+            let window_seconds = RESIGN_CONFIRMATION_WINDOW.as_secs();
+            Self::send_response(tx, WebSocketResponse::ConfirmResign { window_seconds }).await?;
+          }
+          Err(err) if err.downcast_ref::<NotAuthorized>().is_some() => {
+            Self::send_response(tx, WebSocketResponse::NotAuthorized { game_token }).await?;
+          }
+          Err(err) => {
+            let message = err.to_string();
+            Self::send_response(tx, WebSocketResponse::Error { message }).await?;
+          }
+        }
+      }
+      WebSocketRequest::ValidateAction { game_token, action } => {
+        match global_state.validate_action(self.connection_id, game_token, &action).await {
+          Err(err) if err.downcast_ref::<NotAuthorized>().is_some() => {
+            Self::send_response(tx, WebSocketResponse::NotAuthorized { game_token }).await?;
+          }
+          result => {
+            let error = result.err().map(|err| err.to_string());
+            Self::send_response(
+              tx,
+              WebSocketResponse::ActionValidation { game_token, error },
+            )
+            .await?;
+          }
+        }
       }
     }
     Ok(())
   }
 
-  async fn main_loop(&mut self, ws: ws::WebSocket, global_state: &GlobalState) {
+  async fn send_chat(&mut self, text: String, global_state: &GlobalState) -> Result<(), Error> {
+    if text.chars().count() > MAX_CHAT_LENGTH {
+      bail!(
+        "Chat messages are limited to {} characters",
+        MAX_CHAT_LENGTH
+      );
+    }
+    let lobby_members: Vec<ConnectionId> =
+      global_state.main_lobby.read().await.iter().copied().collect();
+    if !lobby_members.contains(&self.connection_id) {
+      bail!("Join the lobby to chat");
+    }
+    self.check_chat_rate_limit()?;
+    let chat = WebSocketResponse::Chat {
+      from: self.connection_id,
+      text,
+    };
+    global_state.send_to(&lobby_members, &chat).await;
+    Ok(())
+  }
+
+  async fn main_loop(&mut self, ws: ws::WebSocket, global_state: &'static GlobalState) {
     let (mut tx, mut rx) = ws.split();
     loop {
       tokio::select! {
@@ -95,7 +1337,7 @@ impl ConnectionState {
           match ws_message {
             Some(Ok(msg)) => {
               if let Ok(text) = msg.to_str() {
-                if let Err(err) = self.handle_message(text, &mut tx).await {
+                if let Err(err) = self.handle_message(text, &mut tx, global_state).await {
                   println!("Error handling message: {}", err);
                   break;
                 }
@@ -119,6 +1361,12 @@ impl ConnectionState {
           }
         }
 
+        // Our channel kept overflowing, so stop wasting effort on this connection.
+        _ = self.kicked.notified() => {
+          println!("Dropping lagging connection {}", self.connection_id);
+          break;
+        }
+
         // Handle a message from another thread.
         connection_message = self.wakeup_channel_rx.recv() => {
           match connection_message {
@@ -126,7 +1374,13 @@ impl ConnectionState {
               println!("Sunset");
               break;
             }
-            None => {
+            Some(ConnectionMessage::Send(text)) => {
+              if let Err(err) = tx.send(ws::Message::text(text)).await {
+                println!("Error writing to websocket: {}", err);
+                break;
+              }
+            }
+            None => {
               println!("Websocket closed");
               break;
             }
@@ -138,20 +1392,108 @@ impl ConnectionState {
 }
 
 impl GlobalState {
-  fn new() -> Self {
+  fn new(
+    maps_dir: PathBuf,
+    maps: MapRegistry,
+    saves_dir: Option<PathBuf>,
+    admin_token: Option<String>,
+  ) -> Self {
     Self {
       connections: RwLock::new(HashMap::new()),
-      main_lobby:  RwLock::new(HashSet::new()),
-      games:       RwLock::new(HashMap::new()),
+      main_lobby: RwLock::new(HashSet::new()),
+      games: RwLock::new(HashMap::new()),
+      maps_dir,
+      saves_dir,
+      maps: RwLock::new(maps),
+      map_cache: MapCache::default(),
+      audit_log: RwLock::new(VecDeque::new()),
+      hosted_games: RwLock::new(HostedGames::default()),
+      admin_token,
+    }
+  }
+
+  /// Takes the connection out of the lobby. Returns whether it was still waiting there, as opposed
+  /// to already having been matched into a game (or never having joined).
+  async fn leave_lobby(&self, connection_id: ConnectionId) -> bool {
+    self.main_lobby.write().await.remove(&connection_id)
+  }
+
+  /// Picks the `count` longest-waiting connections out of the lobby and removes them, all under one
+  /// write guard. Returns `None`, leaving the lobby untouched, if fewer than `count` are waiting.
+  async fn drain_lobby(&self, count: usize) -> Option<Vec<ConnectionId>> {
+    let mut lobby = self.main_lobby.write().await;
+    if lobby.len() < count {
+      return None;
+    }
+    // Connection ids are handed out in order, so the lowest ids have been waiting the longest.
+    let mut waiting: Vec<ConnectionId> = lobby.iter().copied().collect();
+    waiting.sort_unstable();
+    waiting.truncate(count);
+    for connection_id in &waiting {
+      lobby.remove(connection_id);
+    }
+    Some(waiting)
+  }
+
+  /// Seats `players`, freshly drained from the lobby, in a new game on a generated map, and tells
+  /// each of them which seat they got. The server started the game, not them, so it doesn't count
+  /// against anyone's `MAX_HOSTED_GAMES`.
+  async fn start_matched_game(&'static self, players: Vec<ConnectionId>) {
+    let creator = Creator {
+      connection_id: players[0],
+      host:          None,
+    };
+    let timing = GameTiming {
+      tick_seconds: MATCHED_TICK_SECONDS,
+      idle_seconds: DEFAULT_IDLE_SECONDS,
+    };
+    let player_count = players.len();
+    let result = self
+      .create_game(
+        creator,
+        MapChoice::Generated,
+        SeedSource::Random,
+        timing,
+        player_count..=player_count,
+        0,
+      )
+      .await;
+    let game_token = match result {
+      Ok(game_token) => game_token,
+      Err(err) => {
+        let message = format!("Couldn't start a matched game: {}", err);
+        self.send_to(&players, &WebSocketResponse::Error { message }).await;
+        return;
+      }
+    };
+    for (i, &connection_id) in players.iter().enumerate() {
+      let response = match i {
+        0 => Ok(0),
+        _ => self.join_game(connection_id, &game_token).await,
+      };
+      let response = match response {
+        Ok(player_index) => WebSocketResponse::GameJoined {
+          game_token: &game_token,
+          player_index,
+        },
+        Err(err) => WebSocketResponse::Error {
+          message: err.to_string(),
+        },
+      };
+      self.send_to(&[connection_id], &response).await;
     }
   }
 
-  async fn lobby_loop(&self) {
+  async fn lobby_loop(&'static self) {
     loop {
       if IS_SHUTTING_DOWN.load(std::sync::atomic::Ordering::Relaxed) {
         break;
       }
 
+      while let Some(players) = self.drain_lobby(MATCHED_PLAYERS).await {
+        self.start_matched_game(players).await;
+      }
+
       // let mut connections = self.connections.write().await;
       // let mut messages = Vec::new();
       // for connection in connections.iter() {
@@ -170,27 +1512,622 @@ impl GlobalState {
     }
   }
 
+  /// Creates a new game for up to `players.end()` players, with the creator's connection seated as
+  /// player 0 and the last `bots` players controlled by the AI. The game starts ticking once the
+  /// remaining seats are claimed with `JoinGame`, which for a practice game is right away, or after
+  /// `START_GRACE_PERIOD` if at least `players.start()` are in by then.
+  async fn create_game(
+    &'static self,
+    creator: Creator,
+    map: MapChoice,
+    seed: SeedSource,
+    timing: GameTiming,
+    players: RangeInclusive<usize>,
+    bots: usize,
+  ) -> Result<GameToken, Error> {
+    check_game_options(timing, &players, bots)?;
+    let max_players = *players.end();
+    let map_name = match &map {
+      MapChoice::Generated => None,
+      MapChoice::Named { name } => Some(name.clone()),
+    };
+    let state = match map {
+      MapChoice::Generated => self.map_cache.generate_hex_map(seed.resolve(), max_players),
+      MapChoice::Named { name } => {
+        let maps = self.maps.read().await;
+        let map_file = maps.get(&name)?;
+        if map_file.player_count != max_players {
+          bail!(
+            "Map {} is for exactly {} players",
+            name,
+            map_file.player_count
+          );
+        }
+        map_file.to_game_state(seed.resolve())?
+      }
+    };
+    let mut game = Game::configured(state, timing, players, bots);
+    game.map_name = map_name;
+    game.seat(creator.connection_id)?;
+    if let Some(host) = creator.host {
+      self.hosted_games.write().await.claim(host)?;
+    }
+    let game_token = generate_token();
+    self.spawn_game(creator.host, game_token.clone(), game).await;
+    Ok(game_token)
+  }
+
+  /// Registers a game under `game_token` and starts its tick loop, which unregisters it again once
+  /// the game is over (after `FINISHED_GAME_TTL`, if it was played to the end). The loop picks up
+  /// from the game's own `tick_interval` and `next_tick_at`, so this also works for a game restored
+  /// mid-play. If `host` is set, the caller is responsible for counting the game against it in
+  /// `hosted_games`, and the tick loop releases it when the game ends. Games that are already over
+  /// aren't registered at all, and their slot is released right away.
+  async fn spawn_game(&'static self, host: Option<Host>, game_token: GameToken, game: Game) {
+    if game.is_over() {
+      if let Some(host) = host {
+        self.hosted_games.write().await.release(host);
+      }
+      return;
+    }
+    let game = Arc::new(RwLock::new(game));
+    self.games.write().await.insert(game_token.clone(), game.clone());
+    tokio::spawn(game_tick_loop(self, host, game_token, game));
+  }
+
+  /// Lists the games matching `query`, for a lobby browser.
+  async fn list_games(&self, query: &GamesQuery) -> Vec<GameSummary> {
+    let games = self.games.read().await;
+    let mut summaries = vec![];
+    for (game_token, game) in games.iter() {
+      summaries.extend(game.read().await.summary(game_token, query));
+    }
+    summaries
+  }
+
+  async fn get_game(&self, game_token: &str) -> Result<Arc<RwLock<Game>>, Error> {
+    match self.games.read().await.get(game_token) {
+      Some(game) => Ok(game.clone()),
+      None => bail!("Game not found"),
+    }
+  }
+
+  async fn validate_action(
+    &self,
+    connection_id: ConnectionId,
+    game_token: &str,
+    action: &GameAction,
+  ) -> Result<(), Error> {
+    let game = self.get_game(game_token).await?;
+    let game = game.read().await;
+    let player_token = match game.seats.get(&connection_id) {
+      Some(player_token) => player_token,
+      None => return Err(NotAuthorized.into()),
+    };
+    game.state.validate_action(player_token, action)
+  }
+
+  async fn take_action(
+    &self,
+    connection_id: ConnectionId,
+    game_token: &str,
+    action_id: Option<u64>,
+    action: GameAction,
+  ) -> Result<ActionOutcome, Error> {
+    let action_kind = action.kind();
+    let result = match self.get_game(game_token).await {
+      Ok(game) => game.write().await.take_action(connection_id, action_id, action),
+      Err(err) => Err(err),
+    };
+    let entry = AuditEntry {
+      at: unix_millis(Instant::now()),
+      connection_id,
+      game_token: game_token.to_string(),
+      action_kind,
+      error: result.as_ref().err().map(|err| err.to_string()),
+    };
+    let mut audit_log = self.audit_log.write().await;
+    if audit_log.len() >= MAX_AUDIT_ENTRIES {
+      audit_log.pop_front();
+    }
+    audit_log.push_back(entry);
+    result
+  }
+
+  /// Seats the connection in a game that's still waiting for players. If that fills the last seat,
+  /// everyone in the game is told it's starting.
+  async fn join_game(
+    &self,
+    connection_id: ConnectionId,
+    game_token: &str,
+  ) -> Result<PlayerIndex, Error> {
+    let game = self.get_game(game_token).await?;
+    let (player_index, participants) = {
+      let mut game = game.write().await;
+      let player_index = game.seat(connection_id)?;
+      let participants: Vec<ConnectionId> = if game.started {
+        game.seats.keys().copied().collect()
+      } else {
+        vec![]
+      };
+      (player_index, participants)
+    };
+    self
+      .send_to(
+        &participants,
+        &WebSocketResponse::GameStarting { game_token },
+      )
+      .await;
+    Ok(player_index)
+  }
+
+  /// Starts (or changes) the connection's spectating of a game, and returns the board as it now
+  /// sees it, or the result if the game is already over. Players can't spectate their own game,
+  /// even from another connection at the same address, since that would lift their fog. Operators
+  /// who present the `ADMIN_TOKEN` can spectate anything.
+  async fn spectate<'a>(
+    &self,
+    connection_id: ConnectionId,
+    host: Host,
+    admin_token: Option<&str>,
+    game_token: &'a str,
+    player: Option<PlayerIndex>,
+  ) -> Result<WebSocketResponse<'a>, Error> {
+    if let Some(admin_token) = admin_token {
+      self.check_admin(admin_token)?;
+    }
+    let same_host: HashSet<ConnectionId> = {
+      let connections = self.connections.read().await;
+      let same_host = connections.iter().filter(|(_, connection)| connection.host == host);
+      same_host.map(|(&other, _)| other).chain([connection_id]).collect()
+    };
+    let game = self.get_game(game_token).await?;
+    let mut game = game.write().await;
+    if admin_token.is_none() && game.has_seat_among(&same_host) {
+      bail!("Players can't spectate their own game");
+    }
+    if let Some(player) = player {
+      if player >= game.state.player_states.len() {
+        bail!("Player {} not found", player);
+      }
+    }
+    Ok(game.spectate(game_token, connection_id, player))
+  }
+
+  /// Removes a player from a game on an operator's behalf, and tells everyone seated in it.
+  async fn eliminate_player(
+    &self,
+    admin_token: &str,
+    game_token: &str,
+    player_index: PlayerIndex,
+  ) -> Result<(), Error> {
+    self.check_admin(admin_token)?;
+    let game = self.get_game(game_token).await?;
+    let participants: Vec<ConnectionId> = {
+      let mut game = game.write().await;
+      game.state.eliminate_player(player_index)?;
+      game.seats.keys().copied().collect()
+    };
+    println!(
+      "Eliminated player {} from game {}",
+      player_index, game_token
+    );
+    let eliminated = WebSocketResponse::PlayerEliminated {
+      game_token,
+      player_index,
+    };
+    self.send_to(&participants, &eliminated).await;
+    Ok(())
+  }
+
+  async fn game_config(&self, game_token: &str) -> Result<GameConfig, Error> {
+    let game = self.get_game(game_token).await?;
+    let config = game.read().await.state.config.clone();
+    Ok(config)
+  }
+
+  async fn combat_stats(&self, admin_token: &str, game_token: &str) -> Result<CombatStats, Error> {
+    self.check_admin(admin_token)?;
+    let game = self.get_game(game_token).await?;
+    let combat_stats = game.read().await.combat_stats;
+    Ok(combat_stats)
+  }
+
+  fn save_path(&self, game_token: &str) -> Option<PathBuf> {
+    let saves_dir = self.saves_dir.as_ref()?;
+    Some(saves_dir.join(format!("{}.json", game_token)))
+  }
+
+  /// Writes the game to the saves directory, if there is one. A failed save is only logged: the
+  /// game plays on, it just won't survive a restart.
+  async fn save_game(&self, game_token: &str, game: &RwLock<Game>) {
+    let path = match self.save_path(game_token) {
+      Some(path) => path,
+      None => return,
+    };
+    let saved = game.read().await.to_saved();
+    // Write to the side and rename over, so a crash mid-write can't leave a truncated save.
+    let partial_path = path.with_extension("json.partial");
+    let result = serde_json::to_string(&saved)
+      .map_err(Error::from)
+      .and_then(|json| Ok(std::fs::write(&partial_path, json)?))
+      .and_then(|()| Ok(std::fs::rename(&partial_path, &path)?));
+    if let Err(err) = result {
+      println!("Couldn't save game {}: {}", game_token, err);
+    }
+  }
+
+  fn delete_save(&self, game_token: &str) {
+    if let Some(path) = self.save_path(game_token) {
+      if let Err(err) = std::fs::remove_file(&path) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+          println!("Couldn't delete save {}: {}", path.display(), err);
+        }
+      }
+    }
+  }
+
+  /// Picks every game in the saves directory back up where it left off, with its own tick interval
+  /// and next deadline. Games that never started are dropped, since everyone waiting in them is
+  /// gone, and finished games are too. Returns how many games were restored.
+  async fn restore_games(&'static self) -> Result<usize, Error> {
+    let saves_dir = match &self.saves_dir {
+      Some(saves_dir) => saves_dir,
+      None => return Ok(0),
+    };
+    let mut restored = 0;
+    for entry in std::fs::read_dir(saves_dir)? {
+      let path = entry?.path();
+      if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        continue;
+      }
+      let game_token = match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(game_token) => game_token.to_string(),
+        None => continue,
+      };
+      let saved: SavedGame = match std::fs::read_to_string(&path)
+        .map_err(Error::from)
+        .and_then(|json| Ok(serde_json::from_str(&json)?))
+      {
+        Ok(saved) => saved,
+        Err(err) => {
+          println!("Skipping unreadable save {}: {}", path.display(), err);
+          continue;
+        }
+      };
+      let mut game = Game::from_saved(saved);
+      if !game.started || game.is_over() {
+        self.delete_save(&game_token);
+        continue;
+      }
+      // Scripts aren't saved, but the map they came from still has them.
+      if let Some(map_name) = &game.map_name {
+        if let Ok(map_file) = self.maps.read().await.get(map_name) {
+          if let Some(script) = &map_file.script {
+            game.state.scenario = Some(Box::new(script.clone()));
+          }
+        }
+      }
+      self.spawn_game(None, game_token, game).await;
+      restored += 1;
+    }
+    Ok(restored)
+  }
+
+  /// Re-reads the maps directory and swaps in whatever loads. Games already running keep the map
+  /// they were built from. If nothing valid is found the current maps are kept, since that's far
+  /// more likely to be a botched upload than an operator deliberately removing every map.
+  async fn reload_maps(&self) -> Result<Vec<String>, Error> {
+    let maps = MapRegistry::load_directory(&self.maps_dir)?;
+    if maps.is_empty() {
+      bail!(
+        "No valid maps found in {}, keeping the current maps",
+        self.maps_dir.display()
+      );
+    }
+    let names: Vec<String> = maps.names().map(|name| name.to_string()).collect();
+    *self.maps.write().await = maps;
+    println!("Reloaded maps: {:?}", names);
+    Ok(names)
+  }
+
+  fn check_admin(&self, admin_token: &str) -> Result<(), Error> {
+    match &self.admin_token {
+      Some(expected) if expected == admin_token => Ok(()),
+      _ => bail!("Not authorized to administer games"),
+    }
+  }
+
+  async fn ack_turn(
+    &self,
+    connection_id: ConnectionId,
+    game_token: &str,
+    turn: u64,
+  ) -> Result<(), Error> {
+    let game = self.get_game(game_token).await?;
+    let mut game = game.write().await;
+    if !game.seats.contains_key(&connection_id) {
+      return Err(NotAuthorized.into());
+    }
+    game.acked_turns.insert(connection_id, turn);
+    Ok(())
+  }
+
+  /// Rewinds a practice game, and sends everyone watching it the restored board.
+  async fn rewind(
+    &self,
+    connection_id: ConnectionId,
+    game_token: &str,
+    turns: u64,
+  ) -> Result<(), Error> {
+    let game = self.get_game(game_token).await?;
+    let updates = {
+      let mut game = game.write().await;
+      if !game.seats.contains_key(&connection_id) {
+        return Err(NotAuthorized.into());
+      }
+      game.rewind(turns)?;
+      game.current_updates(game_token)
+    };
+    for (connection_id, update) in &updates {
+      self.send_to(&[*connection_id], update).await;
+    }
+    Ok(())
+  }
+
+  /// Returns the current state of the game as the connection's player sees it.
+  async fn catch_up<'a>(
+    &self,
+    connection_id: ConnectionId,
+    game_token: &'a str,
+  ) -> Result<WebSocketResponse<'a>, Error> {
+    let game = self.get_game(game_token).await?;
+    let catch_up = game.read().await.catch_up(game_token, connection_id);
+    catch_up
+  }
+
+  /// Pauses or resumes a game, and tells everyone seated in or watching it.
+  async fn set_paused(
+    &self,
+    connection_id: ConnectionId,
+    game_token: &str,
+    paused: bool,
+  ) -> Result<(), Error> {
+    let game = self.get_game(game_token).await?;
+    let updates = game.write().await.set_paused(game_token, connection_id, paused)?;
+    for (connection_id, update) in &updates {
+      self.send_to(&[*connection_id], update).await;
+    }
+    Ok(())
+  }
+
+  /// Queues a response on each connection's wakeup channel without ever waiting. Connections whose
+  /// channel is full or closed simply miss the message, so one stuck client can't hold up everyone
+  /// else, and a connection that stays full for `MAX_LAG_STRIKES` sends in a row is disconnected.
+  async fn send_to(&self, recipients: &[ConnectionId], response: &WebSocketResponse<'_>) {
+    let text = match serde_json::to_string(response) {
+      Ok(text) => text,
+      Err(err) => {
+        println!("Couldn't serialize response: {}", err);
+        return;
+      }
+    };
+    let connections = self.connections.read().await;
+    for connection_id in recipients {
+      if let Some(connection) = connections.get(connection_id) {
+        match connection.notification_channel.try_send(ConnectionMessage::Send(text.clone())) {
+          Ok(()) => connection.lag_strikes.store(0, std::sync::atomic::Ordering::Relaxed),
+          Err(mpsc::error::TrySendError::Full(_)) => {
+            let strikes =
+              connection.lag_strikes.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+            println!(
+              "Dropping message for lagging connection {} ({} in a row)",
+              connection_id, strikes
+            );
+            if strikes >= MAX_LAG_STRIKES {
+              connection.kicked.notify_one();
+            }
+          }
+          Err(err) => println!("Dropping message for connection {}: {}", connection_id, err),
+        }
+      }
+    }
+  }
+
   fn sunset_lobby(&self) {}
 }
 
+/// Checks the settings a game is being created with against the server's limits.
+fn check_game_options(
+  timing: GameTiming,
+  players: &RangeInclusive<usize>,
+  bots: usize,
+) -> Result<(), Error> {
+  let (min_players, max_players) = (*players.start(), *players.end());
+  if !(MIN_TICK_SECONDS..=MAX_TICK_SECONDS).contains(&timing.tick_seconds) {
+    bail!(
+      "Tick interval must be between {} and {} seconds",
+      MIN_TICK_SECONDS,
+      MAX_TICK_SECONDS
+    );
+  }
+  if !(MIN_IDLE_SECONDS..=MAX_IDLE_SECONDS).contains(&timing.idle_seconds) {
+    bail!(
+      "Idle threshold must be between {} and {} seconds",
+      MIN_IDLE_SECONDS,
+      MAX_IDLE_SECONDS
+    );
+  }
+  if !(MIN_PLAYERS..=MAX_PLAYERS).contains(&max_players) {
+    bail!(
+      "Player count must be between {} and {}",
+      MIN_PLAYERS,
+      MAX_PLAYERS
+    );
+  }
+  if !(MIN_PLAYERS..=max_players).contains(&min_players) {
+    bail!(
+      "Minimum player count must be between {} and {}",
+      MIN_PLAYERS,
+      max_players
+    );
+  }
+  if bots >= max_players {
+    bail!("At least one player must be human");
+  }
+  Ok(())
+}
+
+fn generate_token() -> String {
+  format!("{:016x}", rand::random::<u64>())
+}
+
+async fn game_tick_loop(
+  global_state: &'static GlobalState,
+  host: Option<Host>,
+  game_token: GameToken,
+  game: Arc<RwLock<Game>>,
+) {
+  loop {
+    let next_tick_at = game.read().await.next_tick_at;
+    tokio::time::sleep_until(tokio::time::Instant::from_std(next_tick_at)).await;
+    if IS_SHUTTING_DOWN.load(std::sync::atomic::Ordering::Relaxed) {
+      break;
+    }
+    // The write guard only lives for this statement, so messages are never queued while holding it.
+    let tick = game.write().await.tick(&game_token);
+    for (connection_id, update) in &tick.updates {
+      global_state.send_to(&[*connection_id], update).await;
+    }
+    if tick.finished {
+      break;
+    }
+    global_state.save_game(&game_token, &game).await;
+  }
+  // The save is what lets the game pick back up after the restart.
+  if IS_SHUTTING_DOWN.load(std::sync::atomic::Ordering::Relaxed) {
+    return;
+  }
+  global_state.delete_save(&game_token);
+
+  // However the game ended, nothing more will happen in it, so it stops counting against its
+  // creator's limit right away. A game that was played to the end is kept a while longer, so anyone
+  // who comes to watch it late is told how it went rather than that it doesn't exist.
+  if let Some(host) = host {
+    global_state.hosted_games.write().await.release(host);
+  }
+  if game.read().await.is_over() && !IS_SHUTTING_DOWN.load(std::sync::atomic::Ordering::Relaxed) {
+    tokio::time::sleep(FINISHED_GAME_TTL).await;
+  }
+  global_state.games.write().await.remove(&game_token);
+}
+
+/// Converts an `Instant` to milliseconds since the Unix epoch, for clients to count down to.
+fn unix_millis(at: Instant) -> u64 {
+  let system_at = SystemTime::now() + at.saturating_duration_since(Instant::now());
+  system_at
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// The inverse of `unix_millis`, for deadlines read back from disk. Anything already past comes
+/// back as now.
+fn instant_from_unix_millis(millis: u64) -> Instant {
+  let system_at = UNIX_EPOCH + Duration::from_millis(millis);
+  Instant::now() + system_at.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+}
+
 enum ConnectionMessage {
   Sunset,
+  /// Already-serialized text to forward to the client.
+  Send(String),
 }
 
 struct ConnectionEntry {
+  pub host:                 Host,
   pub notification_channel: mpsc::Sender<ConnectionMessage>,
+  /// How many broadcasts in a row found `notification_channel` full.
+  pub lag_strikes:          AtomicUsize,
+  pub kicked:               Arc<Notify>,
+}
+
+/// Who created a game, for `MAX_HOSTED_GAMES`. Reconnecting mustn't reset the count, so hosts are
+/// known by their address wherever the server can see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Host {
+  Address(IpAddr),
+  /// Only for connections whose address is unknown.
+  Connection(ConnectionId),
+}
+
+/// The connection creating a game, and the host it counts against, if any.
+#[derive(Debug, Clone, Copy)]
+struct Creator {
+  connection_id: ConnectionId,
+  /// `None` for games the server starts on players' behalf, like lobby matches.
+  host:          Option<Host>,
+}
+
+/// How many unfinished games each host has created.
+#[derive(Default)]
+struct HostedGames(HashMap<Host, usize>);
+
+impl HostedGames {
+  /// Counts another game against `host`, unless they already have `MAX_HOSTED_GAMES`.
+  fn claim(&mut self, host: Host) -> Result<(), Error> {
+    let hosted = self.0.entry(host).or_insert(0);
+    if *hosted >= MAX_HOSTED_GAMES {
+      bail!(
+        "You can only host {} unfinished games at once",
+        MAX_HOSTED_GAMES
+      );
+    }
+    *hosted += 1;
+    Ok(())
+  }
+
+  /// Frees up the slot of one of `host`'s games that has ended.
+  fn release(&mut self, host: Host) {
+    if let Some(hosted) = self.0.get_mut(&host) {
+      *hosted -= 1;
+      if *hosted == 0 {
+        self.0.remove(&host);
+      }
+    }
+  }
 }
 
 struct GlobalState {
-  connections: RwLock<HashMap<ConnectionId, Arc<ConnectionEntry>>>,
-  main_lobby:  RwLock<HashSet<ConnectionId>>,
-  games:       RwLock<HashMap<String, Arc<Game>>>,
+  connections:  RwLock<HashMap<ConnectionId, Arc<ConnectionEntry>>>,
+  /// Connections waiting in the lobby. Anything that picks players out of it must check and remove
+  /// them under a single write guard, as `drain_lobby` does, so a `LeaveLobby` can never land in
+  /// between and leave a player placed in a game they just left the lobby for.
+  main_lobby:   RwLock<HashSet<ConnectionId>>,
+  games:        RwLock<HashMap<GameToken, Arc<RwLock<Game>>>>,
+  maps_dir:     PathBuf,
+  /// Where running games are saved after every turn, so they survive a restart. `None` turns saving
+  /// off.
+  saves_dir:    Option<PathBuf>,
+  maps:         RwLock<MapRegistry>,
+  map_cache:    MapCache,
+  /// Every recent action, accepted or not, oldest first.
+  audit_log:    RwLock<VecDeque<AuditEntry>>,
+  hosted_games: RwLock<HostedGames>,
+  /// The secret operators must present to use admin requests. Admin requests are disabled if unset.
+  admin_token:  Option<String>,
 }
 
-async fn user_connected(ws: ws::WebSocket, global_state: &GlobalState) {
-  let mut connection_state = ConnectionState::new();
+async fn user_connected(
+  ws: ws::WebSocket,
+  remote: Option<SocketAddr>,
+  global_state: &'static GlobalState,
+) {
+  let mut connection_state = ConnectionState::new(remote);
   let connection_entry = Arc::new(ConnectionEntry {
+    host:                 connection_state.host,
     notification_channel: connection_state.wakeup_channel_tx.clone(),
+    lag_strikes:          AtomicUsize::new(0),
+    kicked:               connection_state.kicked.clone(),
   });
 
   // Add us to the global connections list.
@@ -204,19 +2141,52 @@ async fn user_connected(ws: ws::WebSocket, global_state: &GlobalState) {
 
   // Remove us from the global connections list.
   global_state.connections.write().await.remove(&connection_state.connection_id);
+  global_state.leave_lobby(connection_state.connection_id).await;
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
   dotenv::dotenv().ok();
 
-  let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::new()));
+  let maps_dir = std::env::var("MAPS_DIR").unwrap_or_else(|_| DEFAULT_MAPS_DIR.to_string());
+  let maps_dir = PathBuf::from(maps_dir);
+  let maps = match MapRegistry::load_directory(&maps_dir) {
+    Ok(maps) => maps,
+    Err(err) => {
+      println!(
+        "Couldn't read maps directory {}: {}",
+        maps_dir.display(),
+        err
+      );
+      MapRegistry::default()
+    }
+  };
+  println!("Loaded maps: {:?}", maps.names().collect::<Vec<_>>());
+
+  let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|admin_token| !admin_token.is_empty());
+
+  let saves_dir = std::env::var("SAVES_DIR").unwrap_or_else(|_| DEFAULT_SAVES_DIR.to_string());
+  let saves_dir = PathBuf::from(saves_dir);
+  std::fs::create_dir_all(&saves_dir)
+    .with_context(|| format!("Couldn't create saves directory {}", saves_dir.display()))?;
+
+  let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::new(
+    maps_dir,
+    maps,
+    Some(saves_dir),
+    admin_token,
+  )));
   let warp_global_state = warp::any().map(move || global_state);
 
+  match global_state.restore_games().await {
+    Ok(restored) => println!("Restored {} saved games", restored),
+    Err(err) => println!("Couldn't restore saved games: {}", err),
+  }
+
   tokio::spawn(global_state.lobby_loop());
 
   // Handle SIGTERM, which is sent by Kubernetes when it wants to shut down the pod.
-  let mut signals = Signals::new(&[SIGTERM]).unwrap();
+  let mut signals = Signals::new(&[SIGTERM]).context("Couldn't register the SIGTERM handler")?;
   thread::spawn(move || {
     for sig in signals.forever() {
       match sig {
@@ -250,17 +2220,1008 @@ async fn main() -> Result<(), Error> {
       "Access-Control-Request-Headers",
       "Content-Type",
       "X-Requested-With",
+      "X-Admin-Token",
     ]);
 
   let ws_endpoint = warp::path!("api" / "game-connection")
     .and(warp::ws())
+    .and(warp::addr::remote())
     .and(warp_global_state)
-    .map(|ws: warp::ws::Ws, gs: &'static GlobalState| {
-      ws.on_upgrade(move |socket| user_connected(socket, gs))
+    .map(
+      |ws: warp::ws::Ws, remote: Option<SocketAddr>, gs: &'static GlobalState| {
+        ws.on_upgrade(move |socket| user_connected(socket, remote, gs))
+      },
+    );
+
+  let games_endpoint = warp::path!("api" / "games")
+    .and(warp::get())
+    .and(warp::query::<GamesQuery>())
+    .and(warp::any().map(move || global_state))
+    .and_then(|query: GamesQuery, gs: &'static GlobalState| async move {
+      Ok::<_, Infallible>(warp::reply::json(&gs.list_games(&query).await))
+    });
+
+  let game_config_endpoint = warp::path!("api" / "game" / String / "config")
+    .and(warp::get())
+    .and(warp::any().map(move || global_state))
+    .and_then(|game_token: String, gs: &'static GlobalState| async move {
+      let reply = match gs.game_config(&game_token).await {
+        Ok(config) => warp::reply::with_status(warp::reply::json(&config), StatusCode::OK),
+        Err(err) => {
+          warp::reply::with_status(warp::reply::json(&err.to_string()), StatusCode::NOT_FOUND)
+        }
+      };
+      Ok::<_, Infallible>(reply)
     });
 
+  let reload_maps_endpoint = warp::path!("api" / "admin" / "reload-maps")
+    .and(warp::post())
+    .and(warp::header::optional::<String>("x-admin-token"))
+    .and(warp::any().map(move || global_state))
+    .and_then(
+      |admin_token: Option<String>, gs: &'static GlobalState| async move {
+        let reply = match gs.check_admin(admin_token.as_deref().unwrap_or("")) {
+          Err(err) => {
+            warp::reply::with_status(warp::reply::json(&err.to_string()), StatusCode::FORBIDDEN)
+          }
+          Ok(()) => match gs.reload_maps().await {
+            Ok(names) => warp::reply::with_status(warp::reply::json(&names), StatusCode::OK),
+            Err(err) => {
+              println!("Map reload failed: {:#}", err);
+              warp::reply::with_status(
+                warp::reply::json(&format!("{:#}", err)),
+                StatusCode::BAD_REQUEST,
+              )
+            }
+          },
+        };
+        Ok::<_, Infallible>(reply)
+      },
+    );
+
+  let audit_log_endpoint = warp::path!("api" / "admin" / "audit-log")
+    .and(warp::get())
+    .and(warp::header::optional::<String>("x-admin-token"))
+    .and(warp::any().map(move || global_state))
+    .and_then(
+      |admin_token: Option<String>, gs: &'static GlobalState| async move {
+        let reply = match gs.check_admin(admin_token.as_deref().unwrap_or("")) {
+          Err(err) => {
+            warp::reply::with_status(warp::reply::json(&err.to_string()), StatusCode::FORBIDDEN)
+          }
+          Ok(()) => {
+            let audit_log = gs.audit_log.read().await;
+            warp::reply::with_status(warp::reply::json(&*audit_log), StatusCode::OK)
+          }
+        };
+        Ok::<_, Infallible>(reply)
+      },
+    );
+
   println!("Starting server");
-  warp::serve(ws_endpoint.with(cors)).run(([127, 0, 0, 1], 12001)).await;
+  warp::serve(
+    ws_endpoint
+      .or(games_endpoint)
+      .or(game_config_endpoint)
+      .or(reload_maps_endpoint)
+      .or(audit_log_endpoint)
+      .with(cors),
+  )
+  .run(([127, 0, 0, 1], 12001))
+  .await;
 
   Ok(())
 }
+
+// The `*_kind` helpers are exhaustive matches, so a new protocol variant won't compile until it's
+// listed in one, and then belongs in the matching `*_keep_their_wire_kinds` test too.
+#[cfg(test)]
+mod tests {
+  use mapwar::{
+    game_state::{CombatMode, Command, PlayerState, Territory, TerritorySort},
+    map_file::{MapFile, MapTerritory},
+  };
+
+  use super::*;
+
+  /// A two-player game on a generated map, where player `i`'s token is `player-{i}`.
+  fn test_game() -> Game {
+    let mut state = mapwar::map_gen::generate_hex_map(1, 2);
+    for player in 0..2 {
+      state.player_indices_by_token.insert(format!("player-{}", player), player);
+    }
+    let mut game = Game::new(Duration::from_secs(1), 2, state);
+    // Connection `i` is seated as player `i`.
+    for player in 0..2 {
+      game.seats.insert(player, format!("player-{}", player));
+    }
+    game
+  }
+
+  /// A game on a row of Land territories, each adjacent to its neighbors, with `contents` on them.
+  /// Connection `i` is seated as player `i`, whose token is `player-{i}`.
+  fn line_game(players: usize, contents: &[Option<(PlayerIndex, i32)>]) -> Game {
+    let mut state = GameState::with_config(0, GameConfig::default());
+    for (i, &contents_here) in contents.iter().enumerate() {
+      let adjacent = [
+        i.checked_sub(1),
+        Some(i + 1).filter(|&next| next < contents.len()),
+      ];
+      state.territories.push(Territory {
+        sort:           TerritorySort::Land,
+        contents:       contents_here,
+        command:        Command::Fortify,
+        adjacent:       adjacent.into_iter().flatten().collect(),
+        render_info:    (i as i32, 0),
+        veterancy:      0,
+        capture_count:  0,
+        previous_owner: None,
+        last_owner:     None,
+      });
+    }
+    for player in 0..players {
+      state.player_states.push(PlayerState::new(player));
+      state.player_indices_by_token.insert(format!("player-{}", player), player);
+    }
+    let mut game = Game::new(Duration::from_secs(1), players, state);
+    for player in 0..players {
+      game.seats.insert(player, format!("player-{}", player));
+    }
+    game
+  }
+
+  #[test]
+  fn idle_players_are_played_by_the_ai() {
+    let mut game = test_game();
+    game.idle_threshold = Duration::from_secs(MAX_IDLE_SECONDS);
+    assert!(game.ai_actions().is_empty());
+    game.idle_threshold = Duration::ZERO;
+    let actions = game.ai_actions();
+    for player in 0..2 {
+      let player_token = format!("player-{}", player);
+      assert!(actions.iter().any(|(token, _)| *token == player_token));
+    }
+  }
+
+  #[test]
+  fn resign_needs_confirming() {
+    let mut game = test_game();
+    let outcome = game.take_action(0, None, GameAction::Resign);
+    assert!(matches!(
+      outcome,
+      Ok(ActionOutcome::ResignNeedsConfirmation)
+    ));
+    assert!(game.state.player_states[0].is_alive);
+    let outcome = game.take_action(0, None, GameAction::Resign);
+    assert!(matches!(outcome, Ok(ActionOutcome::Applied)));
+    assert!(!game.state.player_states[0].is_alive);
+  }
+
+  #[test]
+  fn only_new_valid_actions_count_against_the_rate_limit() {
+    let mut game = test_game();
+    let territory = game
+      .state
+      .territories
+      .iter()
+      .position(|terr| matches!(terr.contents, Some((0, _))))
+      .unwrap();
+    let fortify = GameAction::SetCommand {
+      territory,
+      command: Command::Fortify,
+    };
+    assert!(game.take_action(0, Some(1), fortify.clone()).is_ok());
+    // Retries and rejected actions are free.
+    for _ in 0..MAX_ACTIONS_PER_TURN {
+      assert!(matches!(
+        game.take_action(0, Some(1), fortify.clone()),
+        Ok(ActionOutcome::Duplicate)
+      ));
+      let bad_order = GameAction::SetCommand {
+        territory: game.state.territories.len(),
+        command:   Command::Fortify,
+      };
+      assert!(game.take_action(0, None, bad_order).is_err());
+    }
+    assert_eq!(game.actions_this_turn[0], 1);
+    for _ in 1..MAX_ACTIONS_PER_TURN {
+      assert!(game.take_action(0, None, fortify.clone()).is_ok());
+    }
+    assert!(game.take_action(0, None, fortify).is_err());
+  }
+
+  #[test]
+  fn rewind_restores_an_earlier_turn_but_not_a_finished_game() {
+    let mut game = test_game();
+    game.allow_rewind = true;
+    game.save_snapshot();
+    resolve_turn(&mut game.state, &[]).unwrap();
+    game.save_snapshot();
+    game.state.eliminate_player(1).unwrap();
+    assert!(game.rewind(1).is_err());
+    game.state.player_states[1].is_alive = true;
+    assert!(game.rewind(2).is_ok());
+    assert_eq!(game.state.turn, 0);
+  }
+
+  #[test]
+  fn current_updates_reach_seats_and_spectators() {
+    let mut game = test_game();
+    game.spectators.insert(2, None);
+    game.spectators.insert(3, Some(1));
+    let mut recipients: Vec<ConnectionId> =
+      game.current_updates("game").into_iter().map(|(connection_id, _)| connection_id).collect();
+    recipients.sort();
+    assert_eq!(recipients, [0, 1, 2, 3]);
+  }
+
+  #[test]
+  fn hosted_games_are_capped_per_address() {
+    let mut hosted_games = HostedGames::default();
+    let host = Host::Address(IpAddr::from([127, 0, 0, 1]));
+    for _ in 0..MAX_HOSTED_GAMES {
+      assert!(hosted_games.claim(host).is_ok());
+    }
+    assert!(hosted_games.claim(host).is_err());
+    // Someone else is unaffected, and finishing a game frees its slot.
+    assert!(hosted_games.claim(Host::Connection(0)).is_ok());
+    hosted_games.release(host);
+    assert!(hosted_games.claim(host).is_ok());
+  }
+
+  #[test]
+  fn unstarted_games_expire() {
+    let mut game = test_game();
+    assert!(!game.lobby_expired());
+    if let Some(created_at) = Instant::now().checked_sub(LOBBY_TIMEOUT) {
+      game.created_at = created_at;
+      assert!(game.lobby_expired());
+      game.start();
+      assert!(!game.lobby_expired());
+    }
+  }
+
+  /// The wire `kind` of each `WebSocketRequest`.
+  fn request_kind(request: &WebSocketRequest) -> &'static str {
+    match request {
+      WebSocketRequest::Ping => "ping",
+      WebSocketRequest::JoinLobby => "joinLobby",
+      WebSocketRequest::LeaveLobby => "leaveLobby",
+      WebSocketRequest::CreateGame { .. } => "createGame",
+      WebSocketRequest::TakeAction { .. } => "takeAction",
+      WebSocketRequest::ValidateAction { .. } => "validateAction",
+      WebSocketRequest::ChatMessage { .. } => "chatMessage",
+      WebSocketRequest::StartPracticeGame { .. } => "startPracticeGame",
+      WebSocketRequest::PauseGame { .. } => "pauseGame",
+      WebSocketRequest::ResumeGame { .. } => "resumeGame",
+      WebSocketRequest::JoinGame { .. } => "joinGame",
+      WebSocketRequest::SpectateAs { .. } => "spectateAs",
+      WebSocketRequest::AckTurn { .. } => "ackTurn",
+      WebSocketRequest::Rewind { .. } => "rewind",
+      WebSocketRequest::AdminEliminatePlayer { .. } => "adminEliminatePlayer",
+      WebSocketRequest::AdminGameStats { .. } => "adminGameStats",
+    }
+  }
+
+  #[test]
+  fn requests_keep_their_wire_kinds() {
+    let requests = [
+      r#"{"kind":"ping"}"#,
+      r#"{"kind":"joinLobby"}"#,
+      r#"{"kind":"leaveLobby"}"#,
+      r#"{"kind":"createGame","map":{"kind":"generated"},"tickSeconds":5,"maxPlayers":2}"#,
+      r#"{"kind":"takeAction","gameToken":"g","action":{"kind":"resign"}}"#,
+      r#"{"kind":"validateAction","gameToken":"g","action":{"kind":"resign"}}"#,
+      r#"{"kind":"chatMessage","text":"hi"}"#,
+      r#"{"kind":"startPracticeGame","bots":1}"#,
+      r#"{"kind":"pauseGame","gameToken":"g"}"#,
+      r#"{"kind":"resumeGame","gameToken":"g"}"#,
+      r#"{"kind":"joinGame","gameToken":"g"}"#,
+      r#"{"kind":"spectateAs","gameToken":"g","player":null,"adminToken":null}"#,
+      r#"{"kind":"ackTurn","gameToken":"g","turn":1}"#,
+      r#"{"kind":"rewind","gameToken":"g","turns":1}"#,
+      r#"{"kind":"adminEliminatePlayer","adminToken":"a","gameToken":"g","playerIndex":0}"#,
+      r#"{"kind":"adminGameStats","adminToken":"a","gameToken":"g"}"#,
+    ];
+    for json in requests {
+      let request: WebSocketRequest = serde_json::from_str(json).unwrap();
+      let kind = request_kind(&request);
+      assert!(
+        json.starts_with(&format!(r#"{{"kind":"{}""#, kind)),
+        "{}",
+        json
+      );
+    }
+  }
+
+  /// The wire `kind` of each `WebSocketResponse`.
+  fn response_kind(response: &WebSocketResponse) -> &'static str {
+    match response {
+      WebSocketResponse::Pong => "pong",
+      WebSocketResponse::Error { .. } => "error",
+      WebSocketResponse::ConfirmResign { .. } => "confirmResign",
+      WebSocketResponse::ActionAck { .. } => "actionAck",
+      WebSocketResponse::ActionValidation { .. } => "actionValidation",
+      WebSocketResponse::NotAuthorized { .. } => "notAuthorized",
+      WebSocketResponse::GameCreated { .. } => "gameCreated",
+      WebSocketResponse::GameStarting { .. } => "gameStarting",
+      WebSocketResponse::Chat { .. } => "chat",
+      WebSocketResponse::GamePaused { .. } => "gamePaused",
+      WebSocketResponse::GameUpdate { .. } => "gameUpdate",
+      WebSocketResponse::StateDelta { .. } => "stateDelta",
+      WebSocketResponse::GameEvents { .. } => "gameEvents",
+      WebSocketResponse::GameJoined { .. } => "gameJoined",
+      WebSocketResponse::GameOver { .. } => "gameOver",
+      WebSocketResponse::GameAborted { .. } => "gameAborted",
+      WebSocketResponse::GameExpired { .. } => "gameExpired",
+      WebSocketResponse::PlayerEliminated { .. } => "playerEliminated",
+      WebSocketResponse::GameStats { .. } => "gameStats",
+    }
+  }
+
+  #[test]
+  fn responses_keep_their_wire_kinds() {
+    let game = test_game();
+    let game_token = "g";
+    let view = game.state.player_view(Some(0));
+    let responses = [
+      WebSocketResponse::Pong,
+      WebSocketResponse::Error {
+        message: "oops".to_string(),
+      },
+      WebSocketResponse::ConfirmResign { window_seconds: 10 },
+      WebSocketResponse::ActionAck { action_id: 1 },
+      WebSocketResponse::ActionValidation {
+        game_token,
+        error: None,
+      },
+      WebSocketResponse::NotAuthorized { game_token },
+      WebSocketResponse::GameCreated { game_token },
+      WebSocketResponse::GameStarting { game_token },
+      WebSocketResponse::Chat {
+        from: 0,
+        text: "hi".to_string(),
+      },
+      WebSocketResponse::GamePaused {
+        game_token,
+        paused: true,
+        next_tick_at: None,
+      },
+      game.update(game_token, view.clone(), vec![]),
+      WebSocketResponse::StateDelta {
+        game_token,
+        turn: 1,
+        since_turn: 0,
+        territories: view.changed_territories(&view),
+        scoreboard: game.state.scoreboard(),
+        phase: GamePhase::Planning,
+        events: vec![],
+        next_tick_at: 0,
+      },
+      WebSocketResponse::GameEvents {
+        game_token,
+        events: vec![],
+      },
+      WebSocketResponse::GameJoined {
+        game_token,
+        player_index: 0,
+      },
+      WebSocketResponse::GameOver {
+        game_token,
+        outcome: GameOutcome::Draw,
+        scoreboard: game.state.scoreboard(),
+      },
+      WebSocketResponse::GameAborted { game_token },
+      WebSocketResponse::GameExpired { game_token },
+      WebSocketResponse::PlayerEliminated {
+        game_token,
+        player_index: 0,
+      },
+      WebSocketResponse::GameStats {
+        game_token,
+        combat_stats: CombatStats::default(),
+      },
+    ];
+    for response in responses {
+      let json = serde_json::to_value(&response).unwrap();
+      assert_eq!(
+        json.get("kind").and_then(|kind| kind.as_str()),
+        Some(response_kind(&response))
+      );
+    }
+  }
+
+  #[test]
+  fn players_are_spotted_from_any_of_their_connections() {
+    let game = test_game();
+    assert!(game.has_seat_among(&HashSet::from([1, 7])));
+    assert!(!game.has_seat_among(&HashSet::from([5, 7])));
+  }
+
+  #[test]
+  fn spectating_a_finished_game_gets_the_result() {
+    let mut game = test_game();
+    assert!(matches!(
+      game.spectate("game", 5, None),
+      WebSocketResponse::GameUpdate { .. }
+    ));
+    game.state.eliminate_player(1).unwrap();
+    assert!(matches!(
+      game.spectate("game", 6, Some(0)),
+      WebSocketResponse::GameOver {
+        outcome: GameOutcome::Victory { winner: 0 },
+        ..
+      }
+    ));
+    assert_eq!(game.spectators.keys().collect::<Vec<_>>(), [&5]);
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+  async fn leaving_the_lobby_never_races_into_a_match() {
+    let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::new(
+      PathBuf::new(),
+      MapRegistry::default(),
+      None,
+      None,
+    )));
+    for _ in 0..1000 {
+      global_state.main_lobby.write().await.extend([1, 2]);
+      let leave = tokio::spawn(global_state.leave_lobby(1));
+      let drain = tokio::spawn(global_state.drain_lobby(2));
+      let left = leave.await.unwrap();
+      let drained = drain.await.unwrap();
+      // Whichever reaches the lobby first wins outright: the player either left, or was matched.
+      assert_ne!(left, drained.is_some());
+      if let Some(players) = drained {
+        assert_eq!(players, [1, 2]);
+      }
+      assert!(!global_state.main_lobby.read().await.contains(&1));
+      global_state.main_lobby.write().await.clear();
+    }
+  }
+
+  #[test]
+  fn deltas_only_carry_the_territories_a_turn_touched() {
+    let mut game = line_game(2, &[Some((0, 3)), Some((0, 3)), None, None, Some((1, 3))]);
+    let turn = resolve_turn(&mut game.state, &[]).unwrap();
+    game.record_views(turn.views);
+    game.acked_turns.insert(0, game.state.turn);
+    let grow = |territory| {
+      let command = Command::Grow;
+      (
+        "player-0".to_string(),
+        GameAction::SetCommand { territory, command },
+      )
+    };
+    let turn = resolve_turn(&mut game.state, &[grow(0), grow(1)]).unwrap();
+    let view = turn.views[0].clone();
+    match game.turn_update_for("game", 0, view, vec![]) {
+      Some(WebSocketResponse::StateDelta {
+        territories,
+        scoreboard,
+        ..
+      }) => {
+        let touched: Vec<TerritoryIndex> = territories.iter().map(|&(i, _)| i).collect();
+        assert_eq!(touched, [0, 1]);
+        assert_eq!(scoreboard, game.state.scoreboard());
+      }
+      _ => panic!("expected a delta"),
+    }
+  }
+
+  #[test]
+  fn paused_games_hold_their_turn_until_resumed() {
+    let mut game = test_game();
+    game.spectators.insert(2, None);
+    game.start();
+    let updates = game.set_paused("game", 0, true).unwrap();
+    let mut told: Vec<ConnectionId> =
+      updates.iter().map(|&(connection_id, _)| connection_id).collect();
+    told.sort();
+    assert_eq!(told, [0, 1, 2]);
+    for _ in 0..3 {
+      game.next_tick_at = Instant::now();
+      assert!(game.tick("game").updates.is_empty());
+    }
+    assert_eq!(game.state.turn, 0);
+    game.set_paused("game", 0, false).unwrap();
+    // Resuming gives the turn its full time again, which the test doesn't wait out.
+    assert!(game.next_tick_at > Instant::now());
+    game.next_tick_at = Instant::now();
+    game.tick("game");
+    assert_eq!(game.state.turn, 1);
+  }
+
+  #[test]
+  fn finishing_a_game_tells_everyone_the_result() {
+    let mut game = line_game(2, &[Some((0, 8)), Some((1, 1))]);
+    game.state.config.combat_mode = CombatMode::Deterministic;
+    game.spectators.insert(2, None);
+    game.start();
+    let attack = GameAction::SetCommand {
+      territory: 0,
+      command:   Command::Attack { target: 1 },
+    };
+    game.take_action(0, None, attack).unwrap();
+    game.next_tick_at = Instant::now();
+    let tick = game.tick("game");
+    assert!(tick.finished);
+    let mut told = vec![];
+    for (connection_id, update) in tick.updates {
+      match update {
+        WebSocketResponse::GameOver {
+          outcome,
+          scoreboard,
+          ..
+        } => {
+          assert_eq!(outcome, GameOutcome::Victory { winner: 0 });
+          assert_eq!(scoreboard, game.state.scoreboard());
+          assert_eq!(
+            (scoreboard[0].territories, scoreboard[1].territories),
+            (1, 0)
+          );
+        }
+        _ => panic!("expected only GameOver"),
+      }
+      told.push(connection_id);
+    }
+    told.sort();
+    assert_eq!(told, [0, 1, 2]);
+    // The finished game takes no more actions, but still answers late spectators.
+    assert!(game.take_action(0, None, GameAction::Resign).is_err());
+    assert!(matches!(
+      game.spectate("game", 3, None),
+      WebSocketResponse::GameOver { .. }
+    ));
+  }
+
+  #[tokio::test]
+  async fn matched_games_dont_use_up_hosting_slots() {
+    let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::new(
+      PathBuf::new(),
+      MapRegistry::default(),
+      None,
+      None,
+    )));
+    global_state.main_lobby.write().await.extend([1, 2]);
+    let players = global_state.drain_lobby(MATCHED_PLAYERS).await.unwrap();
+    global_state.start_matched_game(players).await;
+    assert_eq!(global_state.games.read().await.len(), 1);
+    assert!(global_state.hosted_games.read().await.0.is_empty());
+  }
+
+  #[test]
+  fn restored_games_pick_up_ticking_where_they_left_off() {
+    let mut game = test_game();
+    game.tick_interval = Duration::from_millis(200);
+    game.start();
+    game.next_tick_at = Instant::now();
+    game.tick("game");
+    assert_eq!(game.state.turn, 1);
+    let mut restored = Game::from_saved(game.to_saved());
+    assert_eq!(restored.tick_interval, game.tick_interval);
+    assert_eq!(restored.state.turn, 1);
+    assert!(restored.started && restored.seats.is_empty());
+    // Unix milliseconds lose a little precision on the way through.
+    let drift = match restored.next_tick_at > game.next_tick_at {
+      true => restored.next_tick_at - game.next_tick_at,
+      false => game.next_tick_at - restored.next_tick_at,
+    };
+    assert!(drift < Duration::from_millis(50));
+    restored.tick("game");
+    assert_eq!(restored.state.turn, 1);
+    std::thread::sleep(restored.next_tick_at.saturating_duration_since(Instant::now()));
+    restored.tick("game");
+    assert_eq!(restored.state.turn, 2);
+  }
+
+  #[test]
+  fn games_keep_the_tick_interval_they_were_created_with() {
+    let timing = |tick_seconds| GameTiming {
+      tick_seconds,
+      idle_seconds: DEFAULT_IDLE_SECONDS,
+    };
+    assert!(check_game_options(timing(2), &(2..=2), 0).is_ok());
+    assert!(check_game_options(timing(MIN_TICK_SECONDS - 1), &(2..=2), 0).is_err());
+    assert!(check_game_options(timing(MAX_TICK_SECONDS + 1), &(2..=2), 0).is_err());
+    let state = mapwar::map_gen::generate_hex_map(1, 2);
+    let game = Game::configured(state, timing(2), 2..=2, 0);
+    assert_eq!(game.tick_interval, Duration::from_secs(2));
+  }
+
+  #[test]
+  fn retried_actions_only_land_once() {
+    let mut game = line_game(3, &[Some((0, 3)), Some((1, 3)), Some((2, 3))]);
+    game.state.player_states[0].alliance_id = Some(1);
+    game.state.player_states[1].alliance_id = Some(1);
+    game.state.player_states[0].gold = 10;
+    let donate = GameAction::Donate {
+      to:       1,
+      gold:     3,
+      research: 0,
+    };
+    assert!(matches!(
+      game.take_action(0, Some(7), donate.clone()),
+      Ok(ActionOutcome::Applied)
+    ));
+    assert!(matches!(
+      game.take_action(0, Some(7), donate.clone()),
+      Ok(ActionOutcome::Duplicate)
+    ));
+    assert_eq!(game.state.player_states[1].gold, 3);
+    assert!(matches!(
+      game.take_action(0, Some(8), donate),
+      Ok(ActionOutcome::Applied)
+    ));
+    assert_eq!(game.state.player_states[1].gold, 6);
+  }
+
+  /// Registers a connection the way `user_connected` does, without a socket behind it.
+  async fn connect(global_state: &GlobalState) -> ConnectionState {
+    let connection = ConnectionState::new(None);
+    let entry = Arc::new(ConnectionEntry {
+      host:                 connection.host,
+      notification_channel: connection.wakeup_channel_tx.clone(),
+      lag_strikes:          AtomicUsize::new(0),
+      kicked:               connection.kicked.clone(),
+    });
+    global_state.connections.write().await.insert(connection.connection_id, entry);
+    connection
+  }
+
+  #[tokio::test]
+  async fn lobby_chat_reaches_only_lobby_members() {
+    let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::new(
+      PathBuf::new(),
+      MapRegistry::default(),
+      None,
+      None,
+    )));
+    let mut connections = vec![];
+    for _ in 0..3 {
+      connections.push(connect(global_state).await);
+    }
+    global_state
+      .main_lobby
+      .write()
+      .await
+      .extend([connections[0].connection_id, connections[1].connection_id]);
+    connections[0].send_chat("gl hf".to_string(), global_state).await.unwrap();
+    for listener in &mut connections[..2] {
+      match listener.wakeup_channel_rx.try_recv() {
+        Ok(ConnectionMessage::Send(text)) => assert!(text.contains("gl hf")),
+        _ => panic!("Lobby members should hear the chat"),
+      }
+    }
+    assert!(connections[2].wakeup_channel_rx.try_recv().is_err());
+    assert!(connections[2].send_chat("hello?".to_string(), global_state).await.is_err());
+  }
+
+  #[test]
+  fn only_seated_connections_act_and_only_as_themselves() {
+    let mut game = line_game(2, &[Some((0, 3)), Some((1, 3))]);
+    let fortify = |territory| GameAction::SetCommand {
+      territory,
+      command: Command::Fortify,
+    };
+    let refused = game.take_action(5, None, fortify(0)).err().unwrap();
+    assert!(refused.downcast_ref::<NotAuthorized>().is_some());
+    // Connection 1 is seated as player 1, so player 0's territory isn't theirs to order.
+    assert!(game.take_action(1, None, fortify(0)).is_err());
+    assert!(game.take_action(1, None, fortify(1)).is_ok());
+  }
+
+  #[test]
+  fn practice_games_start_at_once_and_advance() {
+    let timing = GameTiming {
+      tick_seconds: PRACTICE_TICK_SECONDS,
+      idle_seconds: DEFAULT_IDLE_SECONDS,
+    };
+    assert!(check_game_options(timing, &(4..=4), 3).is_ok());
+    let state = mapwar::map_gen::generate_hex_map(1, 4);
+    let mut game = Game::configured(state, timing, 4..=4, 3);
+    assert_eq!(game.seat(0).unwrap(), 0);
+    assert!(game.started);
+    assert_eq!(game.state.player_states.len(), 4);
+    assert_eq!(game.bot_players.len(), 3);
+    game.next_tick_at = Instant::now();
+    game.tick("practice");
+    assert_eq!(game.state.turn, 1);
+  }
+
+  #[test]
+  fn joining_needs_an_open_seat_in_a_game_that_hasnt_started() {
+    let timing = GameTiming {
+      tick_seconds: MATCHED_TICK_SECONDS,
+      idle_seconds: DEFAULT_IDLE_SECONDS,
+    };
+    let state = mapwar::map_gen::generate_hex_map(1, 3);
+    let mut game = Game::configured(state, timing, 2..=3, 0);
+    assert_eq!(game.seat(0).unwrap(), 0);
+    assert_eq!(game.seat(1).unwrap(), 1);
+    assert!(!game.started);
+    let mut in_progress = Game::configured(game.state.clone(), timing, 2..=3, 0);
+    // Filling the last seat starts the game, so a full game is always in progress too.
+    assert_eq!(game.seat(2).unwrap(), 2);
+    assert!(game.started);
+    assert!(game.seat(3).is_err());
+    assert_eq!(in_progress.seat(0).unwrap(), 0);
+    in_progress.start();
+    assert!(in_progress.seat(1).is_err());
+    assert_eq!(in_progress.seats.len(), 1);
+  }
+
+  #[tokio::test]
+  async fn lagging_spectators_are_dropped_without_holding_up_others() {
+    let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::new(
+      PathBuf::new(),
+      MapRegistry::default(),
+      None,
+      None,
+    )));
+    let mut healthy = connect(global_state).await;
+    let stalled = connect(global_state).await;
+    let recipients = [healthy.connection_id, stalled.connection_id];
+    // The stalled connection never reads, so its channel fills up and then every send strikes out.
+    for _ in 0..stalled.wakeup_channel_tx.max_capacity() + MAX_LAG_STRIKES {
+      global_state.send_to(&recipients, &WebSocketResponse::Pong).await;
+      assert!(matches!(
+        healthy.wakeup_channel_rx.try_recv(),
+        Ok(ConnectionMessage::Send(_))
+      ));
+    }
+    let kicked = tokio::time::timeout(Duration::from_secs(1), stalled.kicked.notified()).await;
+    assert!(kicked.is_ok());
+  }
+
+  #[test]
+  fn countdowns_advance_a_tick_per_turn_and_stop_while_paused() {
+    let mut game = test_game();
+    game.tick_interval = Duration::from_millis(200);
+    game.start();
+    let tick = |game: &mut Game| {
+      std::thread::sleep(game.next_tick_at.saturating_duration_since(Instant::now()));
+      let deadlines: Vec<u64> = game
+        .tick("game")
+        .updates
+        .into_iter()
+        .filter_map(|(_, response)| match response {
+          WebSocketResponse::GameUpdate { next_tick_at, .. }
+          | WebSocketResponse::StateDelta { next_tick_at, .. } => Some(next_tick_at),
+          _ => None,
+        })
+        .collect();
+      deadlines.first().copied()
+    };
+    let first = tick(&mut game).unwrap();
+    let second = tick(&mut game).unwrap();
+    // Both deadlines are read off the clock, so allow a little slack.
+    assert!((200..250).contains(&(second - first)), "{}", second - first);
+    let paused = game.set_paused("game", 0, true).unwrap();
+    assert!(paused.iter().all(|(_, response)| matches!(
+      response,
+      WebSocketResponse::GamePaused {
+        next_tick_at: None,
+        ..
+      }
+    )));
+    assert_eq!(tick(&mut game), None);
+    assert_eq!(tick(&mut game), None);
+    let resumed = match game.set_paused("game", 0, false).unwrap().pop() {
+      Some((_, WebSocketResponse::GamePaused { next_tick_at, .. })) => next_tick_at.unwrap(),
+      _ => panic!("Resuming should announce the next deadline"),
+    };
+    assert!(resumed >= second + 400);
+    let third = tick(&mut game).unwrap();
+    assert!(
+      (200..250).contains(&(third - resumed)),
+      "{}",
+      third - resumed
+    );
+  }
+
+  #[test]
+  fn rejoining_players_catch_up_on_the_current_turn() {
+    let mut game = test_game();
+    game.start();
+    for _ in 0..2 {
+      game.next_tick_at = Instant::now();
+      game.tick("game");
+    }
+    match game.catch_up("game", 1) {
+      Ok(WebSocketResponse::GameUpdate { view, phase, .. }) => {
+        assert_eq!(view.turn, 2);
+        assert_eq!(phase, GamePhase::Planning);
+      }
+      _ => panic!("Seated players should get the board right away"),
+    }
+    let refused = game.catch_up("game", 7).err().unwrap();
+    assert!(refused.downcast_ref::<NotAuthorized>().is_some());
+  }
+
+  #[tokio::test]
+  async fn reloading_maps_picks_up_new_ones_and_keeps_them_through_bad_uploads() {
+    let maps_dir = std::env::temp_dir().join(format!("mapwar-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&maps_dir).unwrap();
+    let global_state: &'static GlobalState = Box::leak(Box::new(GlobalState::new(
+      maps_dir.clone(),
+      MapRegistry::default(),
+      None,
+      None,
+    )));
+    let territory = |adjacent, x, owner| MapTerritory {
+      sort:        TerritorySort::Land,
+      adjacent:    vec![adjacent],
+      render_info: (x, 0),
+      contents:    Some((owner, 3)),
+    };
+    let duel = MapFile {
+      player_count:    2,
+      territories:     vec![territory(1, 0, 0), territory(0, 1, 1)],
+      starting_levels: vec![],
+      script:          None,
+      render_bounds:   None,
+      check_overlaps:  false,
+    };
+    let duel_path = maps_dir.join("duel.json");
+    std::fs::write(&duel_path, serde_json::to_string(&duel).unwrap()).unwrap();
+    let reloaded = global_state.reload_maps().await;
+    // A botched upload leaves nothing valid behind.
+    std::fs::write(&duel_path, "{ not json").unwrap();
+    let botched = global_state.reload_maps().await;
+    std::fs::remove_dir_all(&maps_dir).unwrap();
+    assert_eq!(reloaded.unwrap(), ["duel"]);
+    assert!(botched.is_err());
+    assert!(global_state.maps.read().await.get("duel").is_ok());
+  }
+
+  #[test]
+  fn game_listings_filter_by_map_and_open_seats() {
+    let mut open_duel = test_game();
+    open_duel.seats.remove(&1);
+    open_duel.map_name = Some("duel".to_string());
+    let mut started_duel = test_game();
+    started_duel.map_name = Some("duel".to_string());
+    started_duel.start();
+    let mut open_generated = test_game();
+    open_generated.seats.remove(&1);
+    let games = [
+      ("a", &open_duel),
+      ("b", &started_duel),
+      ("c", &open_generated),
+    ];
+    let listed = |map: Option<&str>, open| {
+      let query = GamesQuery {
+        map: map.map(|map| map.to_string()),
+        open,
+      };
+      let tokens: Vec<GameToken> = games
+        .iter()
+        .filter_map(|(game_token, game)| game.summary(game_token, &query))
+        .map(|summary| summary.game_token)
+        .collect();
+      tokens
+    };
+    assert_eq!(listed(None, false), ["a", "b", "c"]);
+    assert_eq!(listed(None, true), ["a", "c"]);
+    assert_eq!(listed(Some("duel"), false), ["a", "b"]);
+    assert_eq!(listed(Some("duel"), true), ["a"]);
+  }
+
+  #[test]
+  fn games_start_at_the_minimum_after_the_grace_period() {
+    let timing = GameTiming {
+      tick_seconds: MATCHED_TICK_SECONDS,
+      idle_seconds: DEFAULT_IDLE_SECONDS,
+    };
+    let state = mapwar::map_gen::generate_hex_map(1, 3);
+    let mut game = Game::configured(state, timing, 2..=3, 0);
+    let tick = |game: &mut Game| {
+      game.next_tick_at = Instant::now();
+      game.tick("game").updates
+    };
+    game.seat(0).unwrap();
+    tick(&mut game);
+    assert!(!game.started);
+    game.seat(1).unwrap();
+    // Two players is enough, but the game still waits out the grace period for a third.
+    tick(&mut game);
+    assert!(!game.started);
+    game.created_at = Instant::now() - START_GRACE_PERIOD;
+    let starting = tick(&mut game);
+    assert!(game.started);
+    assert_eq!(starting.len(), 2);
+    assert!(starting
+      .iter()
+      .all(|(_, response)| matches!(response, WebSocketResponse::GameStarting { .. })));
+    // The empty seat was never filled, so the player it was kept for is out.
+    assert!(!game.state.player_states[2].is_alive);
+
+    let state = mapwar::map_gen::generate_hex_map(1, 2);
+    let mut game = Game::configured(state, timing, 2..=2, 0);
+    game.seat(0).unwrap();
+    game.seat(1).unwrap();
+    assert!(game.seat(2).is_err());
+    assert_eq!(game.seats.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn audit_log_records_every_action_without_tokens() {
+    let global_state = GlobalState::new(PathBuf::new(), MapRegistry::default(), None, None);
+    let game = line_game(2, &[Some((0, 3)), Some((1, 3))]);
+    global_state.games.write().await.insert("game".to_string(), Arc::new(RwLock::new(game)));
+    let set_command = |territory| GameAction::SetCommand {
+      territory,
+      command: Command::Grow,
+    };
+    assert!(global_state.take_action(0, "game", None, set_command(0)).await.is_ok());
+    // Player 0 doesn't own territory 1.
+    assert!(global_state.take_action(0, "game", None, set_command(1)).await.is_err());
+    let audit_log = global_state.audit_log.read().await;
+    assert_eq!(audit_log.len(), 2);
+    for (entry, accepted) in audit_log.iter().zip([true, false]) {
+      assert_eq!(entry.connection_id, 0);
+      assert_eq!(entry.game_token, "game");
+      assert_eq!(entry.action_kind, "setCommand");
+      assert_eq!(entry.error.is_none(), accepted);
+    }
+    let json = serde_json::to_string(&*audit_log).unwrap();
+    assert!(!json.contains("player-"));
+  }
+
+  /// A scenario script that panics the first time it runs, to stand in for a bug mid-turn.
+  #[derive(Debug, Clone)]
+  struct PanickingScript;
+
+  impl mapwar::scenario::ScenarioScript for PanickingScript {
+    fn on_turn(&mut self, _state: &mut GameState, _turn: u64) {
+      panic!("scenario bug");
+    }
+
+    fn clone_box(&self) -> Box<dyn mapwar::scenario::ScenarioScript> {
+      Box::new(self.clone())
+    }
+  }
+
+  #[test]
+  fn panicking_turns_abort_only_their_own_game() {
+    let mut broken = test_game();
+    broken.state.scenario = Some(Box::new(PanickingScript));
+    let mut healthy = test_game();
+    for game in [&mut broken, &mut healthy] {
+      game.start();
+      game.next_tick_at = Instant::now();
+    }
+    let tick = broken.tick("broken");
+    assert!(tick.finished && broken.failed);
+    assert_eq!(tick.updates.len(), 2);
+    for (_, update) in &tick.updates {
+      assert!(matches!(update, WebSocketResponse::GameAborted { .. }));
+    }
+    let grow = GameAction::SetCommand {
+      territory: 0,
+      command:   Command::Grow,
+    };
+    assert!(broken.take_action(0, None, grow).is_err());
+    let tick = healthy.tick("healthy");
+    assert!(!tick.finished && !healthy.failed);
+    assert_eq!(healthy.state.turn, 1);
+  }
+
+  #[tokio::test]
+  async fn game_config_is_served_exactly_as_created() {
+    let global_state = GlobalState::new(PathBuf::new(), MapRegistry::default(), None, None);
+    let mut game = line_game(2, &[Some((0, 3)), Some((1, 3))]);
+    let config = GameConfig {
+      combat_mode: CombatMode::Random,
+      dice_sides: 6,
+      max_units: Some(40),
+      victory_condition: mapwar::game_state::VictoryCondition::Economic { gold: 100 },
+      ..GameConfig::default()
+    };
+    game.state.config = config.clone();
+    global_state.games.write().await.insert("game".to_string(), Arc::new(RwLock::new(game)));
+    assert_eq!(global_state.game_config("game").await.unwrap(), config);
+    assert!(global_state.game_config("missing").await.is_err());
+  }
+}
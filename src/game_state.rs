@@ -1,15 +1,50 @@
-use std::{cmp::Ordering, collections::HashMap};
+use std::{cmp::Ordering, collections::HashMap, sync::OnceLock};
 
 use anyhow::{anyhow, bail, Error};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
-use crate::rng::Rng;
+use crate::{rng::Rng, scenario::ScenarioScript};
+
+/// How many steps from a player's starting territories count as "nearby" for balance checks.
+pub const START_RADIUS: u32 = 2;
+
+/// `GameState::best_attack_targets` only recommends attacks at least this likely to succeed.
+pub const RECOMMENDED_WIN_PROBABILITY: f64 = 0.75;
+
+/// How many steps away a player can see from each territory they occupy, before upgrades.
+pub const BASE_VISION_RANGE: u32 = 1;
 
 pub type PlayerToken = String;
 pub type PlayerIndex = usize;
 pub type TerritoryIndex = usize;
+/// A territory sending units somewhere, and how many.
+type SourceUnits = (TerritoryIndex, i32);
+
+/// Mints a token for a new player. Holding a player's token is all it takes to act as them, so it's
+/// 128 bits straight from the OS's CSPRNG, never from the game's predictable `Rng`.
+pub fn generate_player_token() -> PlayerToken {
+  format!(
+    "{:016x}{:016x}",
+    rand::rngs::OsRng.next_u64(),
+    rand::rngs::OsRng.next_u64()
+  )
+}
+
+/// Returned when a player who resigned or was eliminated tries to act. Their token still identifies
+/// them, so they can keep watching, but they can't change the game any more.
+#[derive(Debug)]
+pub struct PlayerEliminated;
+
+impl std::fmt::Display for PlayerEliminated {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Player eliminated")
+  }
+}
 
-#[derive(Debug, Deserialize, ts_rs::TS)]
+impl std::error::Error for PlayerEliminated {}
+
+#[derive(Debug, Clone, Deserialize, ts_rs::TS)]
 #[serde(rename_all = "camelCase", tag = "kind")]
 #[ts(export)]
 pub enum GameAction {
@@ -18,18 +53,61 @@ pub enum GameAction {
     command:   Command,
   },
   Resign,
+  /// Gives some of the player's gold and research to a living ally.
+  Donate {
+    to:       PlayerIndex,
+    gold:     i32,
+    research: i32,
+  },
+}
+
+impl GameAction {
+  /// The wire `kind` of this action. Clients are generated from these, so renaming one is a breaking
+  /// change.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      GameAction::SetCommand { .. } => "setCommand",
+      GameAction::Resign => "resign",
+      GameAction::Donate { .. } => "donate",
+    }
+  }
 }
 
-#[derive(Debug, Serialize, Deserialize, ts_rs::TS)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
 #[serde(rename_all = "camelCase", tag = "kind")]
 #[ts(export)]
 pub enum Command {
-  Attack { target: TerritoryIndex },
+  Attack {
+    target: TerritoryIndex,
+  },
+  /// Attack `target` on each of the next `waves` turns without being re-ordered, stopping early
+  /// once the target falls or the territory is lost.
+  Assault {
+    target: TerritoryIndex,
+    waves:  u32,
+  },
   Fortify,
+  /// Add `GROWTH_PER_TURN` units, plus the owner's growth level, at the point in the turn set by
+  /// `GameConfig::growth_timing`.
   Grow,
+  /// Empty the territory at the start of the turn. If `retreat_to` names an adjacent territory
+  /// the player still holds, half the units (rounded down) fall back there and the rest disband.
+  Abandon {
+    retreat_to: Option<TerritoryIndex>,
+  },
+}
+
+impl Command {
+  /// The territory these orders send the units at this turn, if any.
+  pub fn attack_target(self) -> Option<TerritoryIndex> {
+    match self {
+      Command::Attack { target } | Command::Assault { target, .. } => Some(target),
+      _ => None,
+    }
+  }
 }
 
-#[derive(Debug, Serialize, ts_rs::TS)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
 #[serde(rename_all = "camelCase", tag = "kind")]
 #[ts(export)]
 pub struct PlayerState {
@@ -38,9 +116,305 @@ pub struct PlayerState {
   pub attack_level:  i32,
   pub vision_level:  i32,
   pub growth_level:  i32,
+  pub gold:          i32,
+  pub research:      i32,
+  /// Players with the same alliance id share vision and never fight each other.
+  pub alliance_id:   Option<u32>,
+  /// RGB, always `player_color` of the player's index.
+  pub color:         (u8, u8, u8),
+}
+
+impl PlayerState {
+  pub fn new(player: PlayerIndex) -> Self {
+    Self {
+      is_alive:      true,
+      defense_level: 0,
+      attack_level:  0,
+      vision_level:  0,
+      growth_level:  0,
+      gold:          0,
+      research:      0,
+      alliance_id:   None,
+      color:         player_color(player),
+    }
+  }
+}
+
+/// Upgrade levels a player starts with instead of zero, to handicap stronger players or give
+/// factions their own flavor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind", default)]
+#[ts(export)]
+pub struct PlayerStartLevels {
+  pub defense_level: i32,
+  pub attack_level:  i32,
+  pub vision_level:  i32,
+  pub growth_level:  i32,
+}
+
+/// The RGB colors players are drawn in, by player index.
+pub const PLAYER_PALETTE: [(u8, u8, u8); 8] = [
+  (0xe6, 0x19, 0x4b),
+  (0x43, 0x63, 0xd8),
+  (0x3c, 0xb4, 0x4b),
+  (0xff, 0xe1, 0x19),
+  (0x91, 0x1e, 0xb4),
+  (0xf5, 0x82, 0x31),
+  (0x42, 0xd4, 0xf4),
+  (0xf0, 0x32, 0xe6),
+];
+
+/// Returns the player's color, wrapping around if there are more players than palette entries.
+pub fn player_color(player: PlayerIndex) -> (u8, u8, u8) {
+  PLAYER_PALETTE[player % PLAYER_PALETTE.len()]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum CombatMode {
+  /// Each side rolls a die per half point, and the higher total wins.
+  Random,
+  /// The side with more half points simply wins, with no dice involved. Ties between armies moving
+  /// into the same empty territory go to the lowest-indexed source, so the RNG is never consulted.
+  Deterministic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum MoveMode {
+  /// The whole stack relocates into the captured territory.
+  March,
+  /// Only `SCORCH_GARRISON` units occupy the captured territory, and the rest of the stack is lost.
+  Scorch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum StackPolicy {
+  /// Only the single largest stack moving into an empty territory gets it.
+  Largest,
+  /// All of one alliance's stacks moving into the same empty territory combine, and the side with
+  /// the largest combined stack takes it. The combined stack goes to the first of its owners.
+  Merge,
+}
+
+/// When in the turn `Command::Grow` adds its units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum GrowthTiming {
+  /// Before combat, so the new units help defend this turn.
+  Before,
+  /// After combat, so only stacks that survive the turn grow.
+  After,
+}
+
+/// How many units `Command::Grow` adds each turn, before the owner's growth level.
+pub const GROWTH_PER_TURN: i32 = 1;
+
+/// How many units hold a territory captured under `MoveMode::Scorch`.
+pub const SCORCH_GARRISON: i32 = 1;
+
+/// What standing on one sort of territory does.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct TerrainEffects {
+  /// Flat half defense points for whoever holds the territory.
+  pub defense_bonus:    i32,
+  /// Extra half defense points per unit holding the territory.
+  pub defense_per_unit: i32,
+  /// Extra vision range from the territory.
+  pub vision_bonus:     u32,
+  /// How many times the current per-territory gold income the territory pays each turn.
+  pub gold_yield:       i32,
+  /// Research paid each turn.
+  pub research_yield:   i32,
+}
+
+impl TerrainEffects {
+  const fn new(defense_bonus: i32, defense_per_unit: i32) -> Self {
+    Self {
+      defense_bonus,
+      defense_per_unit,
+      vision_bonus: 0,
+      gold_yield: 0,
+      research_yield: 0,
+    }
+  }
+}
+
+/// The effects of every territory sort, so terrain can be rebalanced without touching combat code.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct TerrainRules {
+  pub land:   TerrainEffects,
+  pub swamp:  TerrainEffects,
+  pub forest: TerrainEffects,
+  pub tower:  TerrainEffects,
+  pub gold:   TerrainEffects,
+  pub lab:    TerrainEffects,
+}
+
+impl TerrainRules {
+  pub fn effects(&self, sort: TerritorySort) -> &TerrainEffects {
+    match sort {
+      TerritorySort::Land => &self.land,
+      TerritorySort::Swamp => &self.swamp,
+      TerritorySort::Forest => &self.forest,
+      TerritorySort::Tower => &self.tower,
+      TerritorySort::Gold => &self.gold,
+      TerritorySort::Lab => &self.lab,
+    }
+  }
+}
+
+impl Default for TerrainRules {
+  fn default() -> Self {
+    // Every sort but Swamp and Forest has always added one half point per unit on defense.
+    Self {
+      land:   TerrainEffects::new(0, 1),
+      swamp:  TerrainEffects::new(-2, 0),
+      forest: TerrainEffects::new(2, 0),
+      tower:  TerrainEffects {
+        vision_bonus: 1,
+        ..TerrainEffects::new(0, 1)
+      },
+      gold:   TerrainEffects {
+        gold_yield: 1,
+        ..TerrainEffects::new(0, 1)
+      },
+      lab:    TerrainEffects {
+        research_yield: 1,
+        ..TerrainEffects::new(0, 1)
+      },
+    }
+  }
+}
+
+/// Shrinks what each Gold territory pays as the game drags on, so turtling gets less attractive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct GoldDecay {
+  /// Gold paid per Gold territory per turn before any decay sets in.
+  pub initial_income: i32,
+  /// The first turn on which income is reduced.
+  pub start_turn:     u64,
+  /// From `start_turn` on, income drops by one every this many turns, bottoming out at zero.
+  pub turns_per_step: u64,
+}
+
+/// Reinforces players who fall far behind, so casual games stay competitive to the end.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct CatchUp {
+  /// Players holding fewer than this percent of the leader's territories get reinforced.
+  pub threshold_percent:  usize,
+  /// The most units a trailing player can receive in a single turn.
+  pub max_units_per_turn: i32,
+}
+
+/// How a game can be won, besides being the last player or alliance standing, which always wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum VictoryCondition {
+  /// Nothing but elimination decides the game.
+  LastStanding,
+  /// Holding at least `percent` of all territories at the end of `turns` turns in a row wins.
+  Domination { percent: usize, turns: u64 },
+  /// Having at least `gold` gold at the end of a turn wins.
+  Economic { gold: i32 },
+  /// Holding `territory` at the end of `turns` turns in a row wins.
+  KingOfTheHill {
+    territory: TerritoryIndex,
+    turns:     u64,
+  },
+}
+
+/// The tunable rules of a game, fixed when the game is created.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct GameConfig {
+  /// Each combat roll is uniform over `0..dice_sides`.
+  pub dice_sides:                      u64,
+  pub combat_mode:                     CombatMode,
+  pub move_mode:                       MoveMode,
+  pub stack_policy:                    StackPolicy,
+  pub growth_timing:                   GrowthTiming,
+  /// If set, no territory can hold more units than this at the end of a turn.
+  pub max_units:                       Option<i32>,
+  /// If set, the game ends in a draw once this many turns have been played.
+  pub max_turns:                       Option<u64>,
+  /// Whether Gold and Lab territories pay out gold and research each turn.
+  pub economy_enabled:                 bool,
+  /// How many half defense points each unit is worth while its territory is attacking. The default
+  /// of 1 means attackers defend at half strength, and 2 removes the penalty entirely.
+  pub attacking_half_defense_per_unit: i32,
+  /// If set, Gold income decays over the course of the game instead of staying at 1 per territory.
+  pub gold_decay:                      Option<GoldDecay>,
+  pub terrain:                         TerrainRules,
+  /// Whether orders stay in force until changed. If not, every territory goes back to `Fortify`
+  /// after each turn, so players have to re-issue their orders.
+  pub orders_persist:                  bool,
+  /// Gold paid to a player whenever their units move into a territory someone else held last, or
+  /// that nobody has ever held. Moving back into a territory you were the last to hold pays nothing.
+  pub capture_bonus_gold:              i32,
+  /// If set, when several stacks move into the same empty territory the two biggest fight for it,
+  /// instead of the biggest simply walking in.
+  pub contested_moves:                 bool,
+  /// If set, half points past this many only count for their square root (rounded down) in combat,
+  /// so huge stacks stay strong without being all but unbeatable.
+  pub combat_soft_cap:                 Option<i32>,
+  /// Added to a defender's combat total for each point of the territory's veterancy. The default
+  /// of 0 turns veterancy off.
+  pub veterancy_bonus:                 i32,
+  /// The percent chance each turn that a random `WorldEvent` strikes. The default of 0 turns them
+  /// off entirely, and leaves the RNG untouched.
+  pub world_event_chance:              u64,
+  /// If set, a player attacked this turn can see the territories the attacks came from, even if
+  /// the attacked territory fell and took their vision with it.
+  pub attacks_reveal_attackers:        bool,
+  pub victory_condition:               VictoryCondition,
+  /// If set, players who fall far enough behind the leader get free units each turn.
+  pub catch_up:                        Option<CatchUp>,
 }
 
-#[derive(Debug, Serialize, ts_rs::TS)]
+impl Default for GameConfig {
+  fn default() -> Self {
+    Self {
+      dice_sides:                      4,
+      combat_mode:                     CombatMode::Random,
+      move_mode:                       MoveMode::March,
+      stack_policy:                    StackPolicy::Largest,
+      growth_timing:                   GrowthTiming::After,
+      max_units:                       None,
+      max_turns:                       None,
+      economy_enabled:                 true,
+      attacking_half_defense_per_unit: 1,
+      gold_decay:                      None,
+      terrain:                         TerrainRules::default(),
+      orders_persist:                  true,
+      capture_bonus_gold:              0,
+      contested_moves:                 false,
+      combat_soft_cap:                 None,
+      veterancy_bonus:                 0,
+      world_event_chance:              0,
+      attacks_reveal_attackers:        false,
+      catch_up:                        None,
+      victory_condition:               VictoryCondition::LastStanding,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
 #[serde(rename_all = "camelCase", tag = "kind")]
 #[ts(export)]
 pub enum TerritorySort {
@@ -58,24 +432,88 @@ pub enum TerritorySort {
   Lab,
 }
 
-#[derive(Debug, Serialize, ts_rs::TS)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
 #[serde(rename_all = "camelCase", tag = "kind")]
 #[ts(export)]
 pub struct Territory {
+  pub sort:           TerritorySort,
+  pub contents:       Option<(PlayerIndex, i32)>,
+  pub command:        Command,
+  pub adjacent:       Vec<TerritoryIndex>,
+  pub render_info:    (i32, i32),
+  /// How many combats the stack here has survived as the defender. Empty territories are always 0.
+  pub veterancy:      i32,
+  /// How many times the territory has changed hands over the course of the game.
+  pub capture_count:  u64,
+  /// The last player to lose the territory in combat, if anyone has.
+  pub previous_owner: Option<PlayerIndex>,
+  /// Whoever held the territory most recently, even if it's empty now.
+  pub last_owner:     Option<PlayerIndex>,
+}
+
+/// How much of a territory a player can make out through the fog of war.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum FogLevel {
+  /// Nothing but the terrain.
+  Hidden,
+  /// Who holds the territory, but not how many units or what they're doing.
+  Partial,
+  /// Everything.
+  Full,
+}
+
+/// A territory as one particular viewer is allowed to see it.
+#[derive(Debug, Clone, PartialEq, Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct TerritoryView {
   pub sort:        TerritorySort,
-  pub contents:    Option<(PlayerIndex, i32)>,
-  pub command:     Command,
   pub adjacent:    Vec<TerritoryIndex>,
   pub render_info: (i32, i32),
+  /// Whether `fog` is `FogLevel::Full`.
+  pub visible:     bool,
+  pub fog:         FogLevel,
+  /// Always `None` when the territory is `Hidden`, and the unit count is always 0 when `Partial`.
+  pub contents:    Option<(PlayerIndex, i32)>,
+  /// Always `None` unless the territory is fully visible and held by the viewer or an ally.
+  pub command:     Option<Command>,
+}
+
+#[derive(Debug, Clone, Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct PlayerView {
+  pub turn:          u64,
+  pub territories:   Vec<TerritoryView>,
+  pub player_states: Vec<PlayerState>,
+}
+
+impl PlayerView {
+  /// Returns the territories that look different in this view than in `earlier`. Views of
+  /// different-sized boards can't be compared, so that gives back every territory.
+  pub fn changed_territories(&self, earlier: &PlayerView) -> Vec<(TerritoryIndex, TerritoryView)> {
+    let same_board = self.territories.len() == earlier.territories.len();
+    self
+      .territories
+      .iter()
+      .enumerate()
+      .filter(|&(i, terr)| !same_board || *terr != earlier.territories[i])
+      .map(|(i, terr)| (i, terr.clone()))
+      .collect()
+  }
 }
 
-#[derive(Debug, Serialize, ts_rs::TS)]
+#[derive(Debug, Clone, Serialize, ts_rs::TS)]
 #[serde(rename_all = "camelCase", tag = "kind")]
 #[ts(export)]
 pub enum AnimationEvent {
   Death {
     render_info: (i32, i32),
     amount:      i32,
+    /// Where each hostile stack that attacked the territory came from.
+    attackers:   Vec<(i32, i32)>,
   },
   Movement {
     render_info_from: (i32, i32),
@@ -84,6 +522,189 @@ pub enum AnimationEvent {
   },
 }
 
+/// A random event that affects the whole board for one turn. See `GameConfig::world_event_chance`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum WorldEvent {
+  /// Every attack die rolls one lower (but never below zero). Only matters in `CombatMode::Random`.
+  Storm,
+  /// Nobody earns any gold or research, and `Grow` orders add no units.
+  Famine,
+}
+
+/// Something that happened in the game worth telling players about, as opposed to an
+/// `AnimationEvent`, which only says what to draw.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum GameEvent {
+  /// `by` moved into `territory`. `from` is whoever held it at the start of the turn, if they lost
+  /// it in combat that same turn.
+  TerritoryCaptured {
+    by:        PlayerIndex,
+    from:      Option<PlayerIndex>,
+    territory: TerritoryIndex,
+  },
+  /// The player lost their last territory, resigned, or was removed by an operator.
+  PlayerEliminated { player: PlayerIndex },
+  /// A `WorldEvent` struck on `turn`.
+  WorldEvent { event: WorldEvent, turn: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum GameOutcome {
+  Ongoing,
+  Victory {
+    winner: PlayerIndex,
+  },
+  /// Every surviving player belongs to the same alliance.
+  AllianceVictory {
+    alliance_id: u32,
+  },
+  Draw,
+}
+
+/// Where the game is in its turn cycle. `resolve_turn` is `Resolving` from the start of `step_time`
+/// until the new board is ready, and `SetCommand` is only accepted while `Planning`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum GamePhase {
+  Planning,
+  Resolving,
+}
+
+/// The box every territory's `render_info` has to fall within, inclusive on both ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct RenderBounds {
+  pub min: (i32, i32),
+  pub max: (i32, i32),
+}
+
+/// A summary of how good a player's starting position is, for checking map fairness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartMetrics {
+  pub player:             PlayerIndex,
+  /// Gold territories within `START_RADIUS` steps of the player's territories.
+  pub nearby_gold:        usize,
+  /// Lab territories within `START_RADIUS` steps of the player's territories.
+  pub nearby_labs:        usize,
+  /// All territories within `START_RADIUS` steps, as a rough measure of room to expand.
+  pub nearby_territories: usize,
+  /// How many territories are connected to the player's, so a player stranded on a small island
+  /// stands out.
+  pub region_size:        usize,
+}
+
+/// The pieces that add up to a territory's half defense points, before any friendly support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefenseTerms {
+  /// Twice the units, or `attacking_half_defense_per_unit` times the units if the territory is
+  /// attacking.
+  pub units:         i32,
+  pub sort:          i32,
+  pub fortify:       i32,
+  pub defense_level: i32,
+}
+
+impl DefenseTerms {
+  pub fn total(&self) -> i32 {
+    self.units + self.sort + self.fortify + self.defense_level
+  }
+}
+
+/// A breakdown of how the next combat at one territory will be decided, for debugging surprising
+/// results. Built by `GameState::explain_combat`.
+#[derive(Debug, Clone)]
+pub struct CombatExplanation {
+  pub territory:                   TerritoryIndex,
+  /// `None` if the territory is empty.
+  pub defense_terms:               Option<DefenseTerms>,
+  /// Friendly territories attacking this one, which add their units to its defense.
+  pub supporters:                  Vec<(TerritoryIndex, i32)>,
+  /// Hostile territories attacking this one, and the units each brings.
+  pub attackers:                   Vec<(TerritoryIndex, i32)>,
+  pub half_defense_points:         i32,
+  pub incoming_half_attack_points: i32,
+  /// The chance that the attackers wipe out the defenders this turn.
+  pub attack_win_probability:      f64,
+}
+
+/// One player's standing, for scoreboards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct PlayerScore {
+  pub player:      PlayerIndex,
+  pub is_alive:    bool,
+  pub territories: usize,
+  pub units:       i32,
+  pub gold:        i32,
+  pub research:    i32,
+}
+
+/// What a player stands to earn this turn, from `GameState::income_preview`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct IncomePreview {
+  pub gold:     i32,
+  pub research: i32,
+  /// Units that `Grow` orders will add across all of the player's stacks.
+  pub growth:   i32,
+}
+
+/// Running totals of how fights went, for telemetry. `step_time` reports one turn's worth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct CombatStats {
+  /// Attack orders against territories not held by the attacker's alliance.
+  pub attacks:    u64,
+  /// Occupied territories whose defenders were wiped out.
+  pub captures:   u64,
+  /// Occupied territories that were attacked and held.
+  pub holds:      u64,
+  /// Defending units lost in captured territories.
+  pub units_lost: u64,
+}
+
+impl CombatStats {
+  pub fn add(&mut self, other: &CombatStats) {
+    self.attacks += other.attacks;
+    self.captures += other.captures;
+    self.holds += other.holds;
+    self.units_lost += other.units_lost;
+  }
+}
+
+/// Everything that happened during a single call to `step_time`.
+#[derive(Debug)]
+pub struct StepReport {
+  pub animation_events: Vec<AnimationEvent>,
+  pub outcome:          GameOutcome,
+  /// Problems that didn't stop the turn from resolving, but likely point at a bug or a bad map edit.
+  pub warnings:         Vec<String>,
+  pub combat_stats:     CombatStats,
+}
+
+/// Everything that came out of `resolve_turn`, ready to be sent to players.
+#[derive(Debug)]
+pub struct TurnReport {
+  pub step:     StepReport,
+  /// What each player sees once the turn is over, indexed by `PlayerIndex`.
+  pub views:    Vec<PlayerView>,
+  /// The animation events each player is allowed to see, indexed by `PlayerIndex`.
+  pub events:   Vec<Vec<AnimationEvent>>,
+  /// Why each pending action that couldn't be applied was rejected.
+  pub rejected: Vec<String>,
+}
+
 /*
 fn same_owner(units_a: Option<(PlayerIndex, i32)>, units_b: Option<(PlayerIndex, i32)>) -> bool {
   match (units_a, units_b) {
@@ -93,205 +714,3219 @@ fn same_owner(units_a: Option<(PlayerIndex, i32)>, units_b: Option<(PlayerIndex,
 }
 */
 
-#[derive(Debug)]
+/// Returns the probability of each possible total when rolling `count` dice uniform over `0..sides`.
+/// Non-positive counts roll nothing, just like in `step_time`.
+fn dice_sum_distribution(count: i32, sides: u64) -> Vec<f64> {
+  let sides = sides.max(1) as usize;
+  let mut distribution = vec![1.0];
+  for _ in 0..count.max(0) {
+    let mut next = vec![0.0; distribution.len() + sides - 1];
+    for (total, chance) in distribution.iter().enumerate() {
+      for roll in 0..sides {
+        next[total + roll] += chance / sides as f64;
+      }
+    }
+    distribution = next;
+  }
+  distribution
+}
+
+/// Serializes in full, player tokens included, so only `to_full_json` should ever send it anywhere.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GameState {
+  pub config:                  GameConfig,
+  pub turn:                    u64,
+  pub phase:                   GamePhase,
   pub rng:                     Rng,
   pub territories:             Vec<Territory>,
   pub player_states:           Vec<PlayerState>,
   pub player_indices_by_token: HashMap<PlayerToken, PlayerIndex>,
+  /// Every `GameEvent` so far, oldest first.
+  pub game_events:             Vec<GameEvent>,
+  /// The `WorldEvent` in effect for the most recently resolved turn, if any.
+  pub world_event:             Option<WorldEvent>,
+  /// Every attack in the most recently resolved turn, as the attacking territory and the player it
+  /// attacked.
+  pub recent_attacks:          Vec<(TerritoryIndex, PlayerIndex)>,
+  /// How many turns in a row each player has met the `victory_condition`, indexed by `PlayerIndex`.
+  /// Only conditions that have to be held for several turns use this.
+  pub victory_streaks:         Vec<u64>,
+  /// Custom logic for scenario maps, run at the start of each turn. Scripts can't be serialized,
+  /// so this doesn't survive `to_full_json`.
+  #[serde(skip)]
+  pub scenario:                Option<Box<dyn ScenarioScript>>,
+  #[serde(skip)]
+  distance_matrix:             OnceLock<Vec<Vec<u32>>>,
+  #[serde(skip)]
+  render_index:                OnceLock<HashMap<(i32, i32), TerritoryIndex>>,
 }
 
 impl GameState {
   pub fn new(seed: u64) -> Self {
+    Self::with_config(seed, GameConfig::default())
+  }
+
+  pub fn with_config(seed: u64, config: GameConfig) -> Self {
     Self {
-      rng:                     Rng::new_from_seed(seed),
-      territories:             vec![],
-      player_states:           vec![],
+      config,
+      turn: 0,
+      phase: GamePhase::Planning,
+      rng: Rng::new_from_seed(seed),
+      territories: vec![],
+      player_states: vec![],
       player_indices_by_token: HashMap::new(),
+      game_events: vec![],
+      world_event: None,
+      recent_attacks: vec![],
+      victory_streaks: vec![],
+      scenario: None,
+      distance_matrix: OnceLock::new(),
+      render_index: OnceLock::new(),
     }
   }
 
-  pub fn process_action(
-    &mut self,
-    player_token: &PlayerToken,
-    action: GameAction,
-  ) -> Result<(), Error> {
-    // Get and validate the player.
-    let player_index = match self.player_indices_by_token.get(player_token) {
-      Some(player_index) => *player_index,
-      None => bail!("Player not found"),
-    };
-    let player = &mut self.player_states[player_index];
-    if !player.is_alive {
-      bail!("Player already dead");
-    }
+  /// Replaces the game's RNG, so tests and tools can make combat come out exactly as they expect.
+  /// `Rng::new_from_seed(rng.state())` gives back an identical RNG, so a saved state can be restored.
+  pub fn set_rng(&mut self, rng: Rng) {
+    self.rng = rng;
+  }
 
-    match action {
-      GameAction::SetCommand { territory, command } => {
-        let command_terr = match self.territories.get(territory) {
-          Some(command_terr) => command_terr,
-          None => bail!("Territory not found"),
-        };
-        let (owner, _) = command_terr.contents.ok_or_else(|| anyhow!("Territory is empty"))?;
-        if owner != player_index {
-          bail!("Player does not own territory");
-        }
-        // Validate the command.
-        match command {
-          Command::Attack { target } => {
-            if let None = self.territories.get(target) {
-              bail!("Target territory not found");
-            }
-            if !command_terr.adjacent.contains(&target) {
-              bail!("Target territory not adjacent");
-            }
+  /// Returns the number of steps from `source` to each territory, stopping the search at
+  /// `max_distance`. Territories that weren't reached get `u32::MAX`.
+  fn bfs_distances(&self, source: TerritoryIndex, max_distance: u32) -> Vec<u32> {
+    self.bfs_distances_through(source, max_distance, |_| true)
+  }
+
+  /// Like `bfs_distances`, but the search only ever steps onto territories that `passable` allows.
+  fn bfs_distances_through(
+    &self,
+    source: TerritoryIndex,
+    max_distance: u32,
+    passable: impl Fn(&Territory) -> bool,
+  ) -> Vec<u32> {
+    let mut distances = vec![u32::MAX; self.territories.len()];
+    distances[source] = 0;
+    let mut frontier = vec![source];
+    for distance in 1..=max_distance {
+      let mut next_frontier = vec![];
+      for terr_index in frontier {
+        for &adj in &self.territories[terr_index].adjacent {
+          if distances[adj] == u32::MAX && passable(&self.territories[adj]) {
+            distances[adj] = distance;
+            next_frontier.push(adj);
           }
-          Command::Fortify | Command::Grow => {}
         }
-        // Set the command.
-        self.territories[territory].command = command;
       }
-      GameAction::Resign => player.is_alive = false,
+      if next_frontier.is_empty() {
+        break;
+      }
+      frontier = next_frontier;
     }
-
-    Ok(())
+    distances
   }
 
-  //pub fn sample_win_rate(&mut self, half_atk: i32, half_def: i32) -> bool {
-  //
-  //}
-
-  pub fn step_time(&mut self) {
-    // Each territory's defense points are:
-    // - The number of units in the territory, or half if it's attacking.
-    // - An adjustment for the territory sort (-1 for swamp, +1 for forest).
-    // - An adjustment for fortification (+1).
-    // - Any friendly units "attacking" the territory sum their units to the defense points.
-    // - The owner's defense level is added to the defense points.
-    let mut half_defense_points: Vec<i32> = self
-      .territories
-      .iter()
-      .map(|terr| {
-        let (owner, units) = match terr.contents {
-          Some(pair) => pair,
-          _ => return 0,
-        };
-        2 * self.player_states[owner].defense_level
-          + match terr.command {
-            Command::Attack { .. } => units,
-            _ => 2 * units,
-          }
-          + match terr.sort {
-            TerritorySort::Swamp => -2,
-            TerritorySort::Forest => 2,
-            _ => units,
+  /// Returns the shortest chain of adjacent territories from `from` to `to`, both included, along
+  /// which the player could push units. Every territory but `to` must belong to the player's
+  /// alliance, while `to` can be anything. Returns `None` if `from` isn't friendly or there's no
+  /// such chain.
+  pub fn attack_path(
+    &self,
+    from: TerritoryIndex,
+    to: TerritoryIndex,
+    player: PlayerIndex,
+  ) -> Option<Vec<TerritoryIndex>> {
+    let is_friendly = |terr_index: TerritoryIndex| matches!(self.territories[terr_index].contents, Some((owner, _)) if self.are_allied(owner, player));
+    if !is_friendly(from) {
+      return None;
+    }
+    // Breadth-first search that only expands through friendly territory, remembering where each
+    // territory was first reached from.
+    let mut came_from = vec![None; self.territories.len()];
+    came_from[from] = Some(from);
+    let mut frontier = vec![from];
+    while !frontier.is_empty() && came_from[to].is_none() {
+      let mut next_frontier = vec![];
+      for terr_index in frontier {
+        for &adj in &self.territories[terr_index].adjacent {
+          if came_from[adj].is_some() {
+            continue;
           }
-          + match terr.command {
-            Command::Fortify => 2,
-            _ => 0,
+          came_from[adj] = Some(terr_index);
+          if is_friendly(adj) {
+            next_frontier.push(adj);
           }
-      })
-      .collect();
-    // Each territory's incoming attack points is simply the sum of the units attacking it,
-    // plus the attacker's attack level for each attacking territory.
-    let mut incoming_half_attack_points: Vec<i32> = vec![0; self.territories.len()];
-    for terr in &self.territories {
-      let (owner, units) = match terr.contents {
-        Some(pair) => pair,
-        None => continue,
-      };
-      if let Command::Attack { target } = terr.command {
-        // Check who owns the target territory.
-        if self.territories[target]
-          .contents
-          .map(|(target_owner, _)| target_owner == owner)
-          .unwrap_or(false)
-        {
-          half_defense_points[target] += units;
-        } else {
-          incoming_half_attack_points[target] += units;
         }
       }
+      frontier = next_frontier;
     }
-    let mut animation_events = vec![];
-    // Have all dying territories lose their units.
-    for (i, terr) in self.territories.iter_mut().enumerate() {
-      let mut defense_sum = 0;
-      for _ in 0..half_defense_points[i] {
-        defense_sum += self.rng.generate() & 0x3;
-      }
-      let mut attack_sum = 0;
-      for _ in 0..incoming_half_attack_points[i] {
-        attack_sum += self.rng.generate() & 0x3;
+    came_from[to]?;
+    let mut path = vec![to];
+    while let Some(&last) = path.last() {
+      if last == from {
+        break;
       }
+      path.push(came_from[last]?);
+    }
+    path.reverse();
+    Some(path)
+  }
 
-      if attack_sum > defense_sum {
-        terr.contents = None;
-        animation_events.push(AnimationEvent::Death {
-          render_info: terr.render_info,
-          // FIXME: Shouldn't be 0.
-          amount:      0, //terr.units,
-        });
+  /// Splits the board into groups of territories that can reach each other, each listed in index
+  /// order. Most maps are a single component, but a map may have deliberately separate islands.
+  pub fn connected_components(&self) -> Vec<Vec<TerritoryIndex>> {
+    self.components_where(|_| true)
+  }
+
+  /// Returns the number of territories in the biggest connected group the player holds, which can
+  /// be well short of their total when their empire is split up.
+  pub fn largest_contiguous(&self, player: PlayerIndex) -> usize {
+    self
+      .components_where(|terr| matches!(terr.contents, Some((owner, _)) if owner == player))
+      .iter()
+      .map(|component| component.len())
+      .max()
+      .unwrap_or(0)
+  }
+
+  /// Like `connected_components`, but only over the territories `include` allows.
+  fn components_where(&self, include: impl Fn(&Territory) -> bool) -> Vec<Vec<TerritoryIndex>> {
+    let mut is_assigned = vec![false; self.territories.len()];
+    let mut components = vec![];
+    for source in 0..self.territories.len() {
+      if is_assigned[source] || !include(&self.territories[source]) {
+        continue;
+      }
+      let component: Vec<TerritoryIndex> = self
+        .bfs_distances_through(source, u32::MAX, &include)
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, distance)| distance != u32::MAX)
+        .map(|(terr_index, _)| terr_index)
+        .collect();
+      for &terr_index in &component {
+        is_assigned[terr_index] = true;
       }
+      components.push(component);
     }
-    // For each territory, move a random territory among all that want to move in with the most units into it.
-    #[derive(Clone, Copy)]
-    struct IncomingEntry {
-      units:            i32,
-      source_territory: Option<TerritoryIndex>,
-      competitor_count: u64,
+    components
+  }
+
+  /// Summarizes each player's position on the current board, which is normally called right after
+  /// the map is generated to check that nobody starts with a big advantage.
+  pub fn starting_balance_report(&self) -> Vec<StartMetrics> {
+    let mut region_sizes = vec![0; self.territories.len()];
+    for component in self.connected_components() {
+      for &terr_index in &component {
+        region_sizes[terr_index] = component.len();
+      }
     }
-    let mut best_incoming = vec![
-      IncomingEntry {
-        units:            -1,
-        source_territory: None,
-        competitor_count: 0,
-      };
-      self.territories.len()
-    ];
-    // We now process each territory, updating where it wants to go.
-    for (i, terr) in self.territories.iter().enumerate() {
-      let units = match terr.contents {
-        Some((_, units)) => units,
-        None => continue,
+    (0..self.player_states.len())
+      .map(|player| {
+        let mut nearby = vec![false; self.territories.len()];
+        for (source, terr) in self.territories.iter().enumerate() {
+          if matches!(terr.contents, Some((owner, _)) if owner == player) {
+            for (terr_index, distance) in
+              self.bfs_distances(source, START_RADIUS).into_iter().enumerate()
+            {
+              nearby[terr_index] |= distance <= START_RADIUS;
+            }
+          }
+        }
+        let count_nearby = |sort: TerritorySort| {
+          self
+            .territories
+            .iter()
+            .zip(&nearby)
+            .filter(|(terr, &near)| near && terr.sort == sort)
+            .count()
+        };
+        StartMetrics {
+          player,
+          nearby_gold: count_nearby(TerritorySort::Gold),
+          nearby_labs: count_nearby(TerritorySort::Lab),
+          nearby_territories: nearby.iter().filter(|&&near| near).count(),
+          region_size: (0..self.territories.len())
+            .filter(
+              |&i| matches!(self.territories[i].contents, Some((owner, _)) if owner == player),
+            )
+            .map(|i| region_sizes[i])
+            .max()
+            .unwrap_or(0),
+        }
+      })
+      .collect()
+  }
+
+  /// Returns the number of steps between every pair of territories, or `u32::MAX` if there's no path.
+  ///
+  /// The matrix is computed on first use and then cached, so call `invalidate_distance_matrix`
+  /// after editing any territory's `adjacent` list.
+  pub fn distance_matrix(&self) -> &[Vec<u32>] {
+    self.distance_matrix.get_or_init(|| {
+      (0..self.territories.len()).map(|source| self.bfs_distances(source, u32::MAX)).collect()
+    })
+  }
+
+  pub fn invalidate_distance_matrix(&mut self) {
+    self.distance_matrix = OnceLock::new();
+  }
+
+  /// Returns the territory drawn at `render_info`, or the lowest-indexed one if several share it.
+  ///
+  /// The lookup table is built on first use and then cached, so call `invalidate_render_index`
+  /// after adding territories or editing any territory's `render_info`.
+  pub fn territory_at_render(&self, render_info: (i32, i32)) -> Option<TerritoryIndex> {
+    let render_index = self.render_index.get_or_init(|| {
+      let mut render_index = HashMap::new();
+      for (i, terr) in self.territories.iter().enumerate() {
+        render_index.entry(terr.render_info).or_insert(i);
+      }
+      render_index
+    });
+    render_index.get(&render_info).copied()
+  }
+
+  pub fn invalidate_render_index(&mut self) {
+    self.render_index = OnceLock::new();
+  }
+
+  /// Hashes everything that decides how the game plays out from here: the turn, every territory,
+  /// every player's state, and the RNG. Clients can compare it against the server's to spot a desync.
+  /// Player tokens are deliberately left out, since they're secret and differ between peers anyway.
+  pub fn state_hash(&self) -> u64 {
+    // FNV-1a over fixed-width little-endian words, so unlike `DefaultHasher` the result can't change
+    // between platforms or compiler versions.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut mix = |word: u64| {
+      for byte in word.to_le_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+      }
+    };
+    mix(self.turn);
+    mix(self.phase as u64);
+    mix(self.rng.state());
+    mix(self.territories.len() as u64);
+    for terr in &self.territories {
+      mix(terr.sort as u64);
+      mix(terr.veterancy as u64);
+      mix(terr.capture_count);
+      mix(terr.previous_owner.map_or(u64::MAX, |owner| owner as u64));
+      mix(terr.last_owner.map_or(u64::MAX, |owner| owner as u64));
+      match terr.contents {
+        Some((owner, units)) => {
+          mix(1);
+          mix(owner as u64);
+          mix(units as u64);
+        }
+        None => mix(0),
+      }
+      match terr.command {
+        Command::Attack { target } => {
+          mix(0);
+          mix(target as u64);
+        }
+        Command::Fortify => mix(1),
+        Command::Grow => mix(2),
+        Command::Assault { target, waves } => {
+          mix(4);
+          mix(target as u64);
+          mix(waves as u64);
+        }
+        Command::Abandon { retreat_to } => {
+          mix(3);
+          mix(retreat_to.map_or(u64::MAX, |retreat_to| retreat_to as u64));
+        }
+      }
+    }
+    mix(self.player_states.len() as u64);
+    for player in &self.player_states {
+      mix(player.is_alive as u64);
+      mix(player.defense_level as u64);
+      mix(player.attack_level as u64);
+      mix(player.vision_level as u64);
+      mix(player.growth_level as u64);
+      mix(player.gold as u64);
+      mix(player.research as u64);
+      mix(player.alliance_id.map_or(u64::MAX, |alliance_id| alliance_id as u64));
+    }
+    for &streak in &self.victory_streaks {
+      mix(streak);
+    }
+    hash
+  }
+
+  /// Returns true if the two players are the same player or share an alliance.
+  pub fn are_allied(&self, a: PlayerIndex, b: PlayerIndex) -> bool {
+    a == b
+      || match (
+        self.player_states[a].alliance_id,
+        self.player_states[b].alliance_id,
+      ) {
+        (Some(alliance_a), Some(alliance_b)) => alliance_a == alliance_b,
+        _ => false,
+      }
+  }
+
+  /// Returns, for each territory, whether the player can currently see it in full.
+  pub fn visible_territories(&self, player: PlayerIndex) -> Vec<bool> {
+    self.fog_levels(player).into_iter().map(|fog| fog == FogLevel::Full).collect()
+  }
+
+  /// Returns how well the player can currently see each territory.
+  ///
+  /// A player fully sees everything within `BASE_VISION_RANGE + vision_level` steps of a territory
+  /// they or an ally occupy (one step further from a Tower), and partially sees one step beyond
+  /// that. Forests are the exception, and can only be seen at all from adjacent tiles. Each
+  /// territory's range uses the vision level of whoever occupies it.
+  ///
+  /// Nothing here is cached between turns: Tower bonuses come straight from the current board, so
+  /// a player who starts on a Tower sees the extra ring from the very first view.
+  pub fn fog_levels(&self, player: PlayerIndex) -> Vec<FogLevel> {
+    let mut fog_levels = vec![FogLevel::Hidden; self.territories.len()];
+    for (source, terr) in self.territories.iter().enumerate() {
+      let vision_level = match terr.contents {
+        Some((owner, _)) if self.are_allied(owner, player) => {
+          self.player_states[owner].vision_level.max(0) as u32
+        }
+        _ => continue,
       };
-      if let Command::Attack { target } = terr.command {
-        // You can only move into empty territories.
-        if self.territories[target].contents.is_some() {
-          continue;
+      let range =
+        BASE_VISION_RANGE + vision_level + self.config.terrain.effects(terr.sort).vision_bonus;
+      for (terr_index, distance) in self.bfs_distances(source, range + 1).into_iter().enumerate() {
+        let is_forest = matches!(self.territories[terr_index].sort, TerritorySort::Forest);
+        let fog = if distance <= 1 || (distance <= range && !is_forest) {
+          FogLevel::Full
+        } else if distance == range + 1 && !is_forest {
+          FogLevel::Partial
+        } else {
+          FogLevel::Hidden
+        };
+        fog_levels[terr_index] = fog_levels[terr_index].max(fog);
+      }
+    }
+    if self.config.attacks_reveal_attackers {
+      for &(source, defender) in &self.recent_attacks {
+        if self.are_allied(defender, player) {
+          fog_levels[source] = FogLevel::Full;
         }
-        // Check if this is a new best.
-        let is_new_best = match units.cmp(&best_incoming[target].units) {
-          Ordering::Greater => {
-            best_incoming[target].competitor_count = 1;
-            true
+      }
+    }
+    fog_levels
+  }
+
+  /// Returns the board as seen by `viewer`, or by a spectator if `viewer` is `None`.
+  pub fn player_view(&self, viewer: Option<PlayerIndex>) -> PlayerView {
+    // Spectators and eliminated players can't affect the game any more, so they see everything.
+    let active_viewer = viewer.filter(|&player| self.player_states[player].is_alive);
+    let fog_levels = match active_viewer {
+      None => vec![FogLevel::Full; self.territories.len()],
+      Some(player) => self.fog_levels(player),
+    };
+    // Orders are only ever shown to the owner's own alliance.
+    let can_see_orders = |terr: &Territory| match (active_viewer, terr.contents) {
+      (None, _) => true,
+      (Some(player), Some((owner, _))) => self.are_allied(owner, player),
+      (Some(_), None) => false,
+    };
+    let territories = self
+      .territories
+      .iter()
+      .zip(fog_levels)
+      .map(|(terr, fog)| TerritoryView {
+        sort: terr.sort,
+        adjacent: terr.adjacent.clone(),
+        render_info: terr.render_info,
+        visible: fog == FogLevel::Full,
+        fog,
+        contents: match fog {
+          FogLevel::Hidden => None,
+          FogLevel::Partial => terr.contents.map(|(owner, _)| (owner, 0)),
+          FogLevel::Full => terr.contents,
+        },
+        command: Some(terr.command).filter(|_| fog == FogLevel::Full && can_see_orders(terr)),
+      })
+      .collect();
+    PlayerView {
+      turn: self.turn,
+      territories,
+      player_states: self.player_states.clone(),
+    }
+  }
+
+  /// Returns the territory that has been captured the most times, favoring the lowest index among
+  /// ties, or `None` if nothing has been captured yet.
+  pub fn most_contested_territory(&self) -> Option<TerritoryIndex> {
+    self
+      .territories
+      .iter()
+      .enumerate()
+      .filter(|(_, terr)| terr.capture_count > 0)
+      .max_by_key(|&(i, terr)| (terr.capture_count, std::cmp::Reverse(i)))
+      .map(|(i, _)| i)
+  }
+
+  /// Returns the player's territories that border a territory held by anyone outside their
+  /// alliance, whether that's a living rival or a neutral stack left behind by an eliminated player.
+  pub fn frontline(&self, player: PlayerIndex) -> Vec<TerritoryIndex> {
+    let is_foreign = |terr_index: &TerritoryIndex| match self.territories[*terr_index].contents {
+      Some((owner, _)) => !self.are_allied(owner, player),
+      None => false,
+    };
+    self
+      .territories
+      .iter()
+      .enumerate()
+      .filter(|(_, terr)| matches!(terr.contents, Some((owner, _)) if owner == player))
+      .filter(|(_, terr)| terr.adjacent.iter().any(is_foreign))
+      .map(|(terr_index, _)| terr_index)
+      .collect()
+  }
+
+  /// Returns every living player who can currently see the territory.
+  pub fn viewers_of(&self, terr: TerritoryIndex) -> Vec<PlayerIndex> {
+    (0..self.player_states.len())
+      .filter(|&player| self.player_states[player].is_alive)
+      .filter(|&player| self.visible_territories(player)[terr])
+      .collect()
+  }
+
+  /// The game as `viewer` may see it, for sending over public channels. This is just `player_view`
+  /// serialized, so it's fogged and never contains a player token.
+  pub fn to_public_json(&self, viewer: Option<PlayerIndex>) -> Result<String, Error> {
+    Ok(serde_json::to_string(&self.player_view(viewer))?)
+  }
+
+  /// Everything needed to restore the game with `from_full_json`, including every player's token
+  /// and the RNG state. Only for persistence and operators, never for players.
+  pub fn to_full_json(&self) -> Result<String, Error> {
+    Ok(serde_json::to_string(self)?)
+  }
+
+  pub fn from_full_json(json: &str) -> Result<GameState, Error> {
+    Ok(serde_json::from_str(json)?)
+  }
+
+  /// Drops the events that happened in territories the player can't see.
+  /// A movement stays visible if the player can see either end of it.
+  ///
+  /// `visible_before` is the player's `visible_territories` from before the turn resolved, and
+  /// anything visible then counts too, so a player still sees the fight that cost them their view
+  /// of a territory.
+  pub fn filter_events_for(
+    &self,
+    events: &[AnimationEvent],
+    player: PlayerIndex,
+    visible_before: &[bool],
+  ) -> Vec<AnimationEvent> {
+    let visible = self.visible_territories(player);
+    let can_see = |render_info| match self.territory_at_render(render_info) {
+      Some(terr_index) => visible[terr_index] || visible_before.get(terr_index) == Some(&true),
+      None => false,
+    };
+    events
+      .iter()
+      .filter(|event| match event {
+        AnimationEvent::Death { render_info, .. } => can_see(*render_info),
+        AnimationEvent::Movement {
+          render_info_from,
+          render_info_to,
+          ..
+        } => can_see(*render_info_from) || can_see(*render_info_to),
+      })
+      .cloned()
+      .collect()
+  }
+
+  /// Drops the captures that happened in territories the player can't see, unless they were the
+  /// one capturing or losing it. Eliminations are public.
+  pub fn filter_game_events_for(
+    &self,
+    events: &[GameEvent],
+    player: PlayerIndex,
+  ) -> Vec<GameEvent> {
+    let visible = self.visible_territories(player);
+    events
+      .iter()
+      .filter(|event| match event {
+        GameEvent::TerritoryCaptured {
+          by,
+          from,
+          territory,
+        } => {
+          *by == player
+            || *from == Some(player)
+            || visible.get(*territory).copied().unwrap_or(false)
+        }
+        GameEvent::PlayerEliminated { .. } | GameEvent::WorldEvent { .. } => true,
+      })
+      .cloned()
+      .collect()
+  }
+
+  pub fn process_action(
+    &mut self,
+    player_token: &PlayerToken,
+    action: GameAction,
+  ) -> Result<(), Error> {
+    let player_index = self.check_action(player_token, &action)?;
+    match action {
+      GameAction::SetCommand { territory, command } => {
+        self.territories[territory].command = command;
+      }
+      GameAction::Resign => {
+        self.player_states[player_index].is_alive = false;
+        self.game_events.push(GameEvent::PlayerEliminated {
+          player: player_index,
+        });
+      }
+      GameAction::Donate { to, gold, research } => {
+        self.player_states[player_index].gold -= gold;
+        self.player_states[player_index].research -= research;
+        // `check_action` already turned away donations that would overflow.
+        self.player_states[to].gold = self.player_states[to].gold.saturating_add(gold);
+        self.player_states[to].research = self.player_states[to].research.saturating_add(research);
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns whether `process_action` would accept the action, without applying it.
+  pub fn validate_action(
+    &self,
+    player_token: &PlayerToken,
+    action: &GameAction,
+  ) -> Result<(), Error> {
+    self.check_action(player_token, action).map(|_| ())
+  }
+
+  /// Everything `process_action` checks before it changes anything. Returns the acting player.
+  fn check_action(
+    &self,
+    player_token: &PlayerToken,
+    action: &GameAction,
+  ) -> Result<PlayerIndex, Error> {
+    // Get and validate the player.
+    let player_index = match self.player_indices_by_token.get(player_token) {
+      Some(player_index) => *player_index,
+      None => bail!("Player not found"),
+    };
+    // The token may outlive its player if the player list is ever compacted, so don't index blindly.
+    let player = match self.player_states.get(player_index) {
+      Some(player) => player,
+      None => bail!("Player not found"),
+    };
+    if !player.is_alive {
+      return Err(PlayerEliminated.into());
+    }
+
+    match *action {
+      GameAction::SetCommand { territory, command } => {
+        if self.phase != GamePhase::Planning {
+          bail!("Orders are closed while the turn resolves");
+        }
+        let command_terr = match self.territories.get(territory) {
+          Some(command_terr) => command_terr,
+          None => bail!("Territory not found"),
+        };
+        let (owner, units) = command_terr.contents.ok_or_else(|| anyhow!("Territory is empty"))?;
+        if owner != player_index {
+          bail!("Player does not own territory");
+        }
+        if units <= 0 {
+          bail!("Territory has no units to order");
+        }
+        // Validate the command.
+        match command {
+          Command::Attack { target } | Command::Assault { target, .. } => {
+            if let None = self.territories.get(target) {
+              bail!("Target territory not found");
+            }
+            if !command_terr.adjacent.contains(&target) {
+              bail!("Target territory not adjacent");
+            }
+            if let Command::Assault { waves: 0, .. } = command {
+              bail!("An assault needs at least one wave");
+            }
           }
-          Ordering::Equal => {
-            best_incoming[target].competitor_count += 1;
-            // If it's a tie, we have a 1/competitor_count chance of being the new best.
-            self.rng.generate() % best_incoming[target].competitor_count == 0
+          Command::Abandon {
+            retreat_to: Some(retreat_to),
+          } => {
+            let retreat_terr = match self.territories.get(retreat_to) {
+              Some(retreat_terr) => retreat_terr,
+              None => bail!("Retreat territory not found"),
+            };
+            if !command_terr.adjacent.contains(&retreat_to) {
+              bail!("Retreat territory not adjacent");
+            }
+            match retreat_terr.contents {
+              Some((retreat_owner, _)) if retreat_owner == player_index => {}
+              _ => bail!("Player does not own retreat territory"),
+            }
           }
-          Ordering::Less => false,
-        };
-        if is_new_best {
-          best_incoming[target].units = units;
-          best_incoming[target].source_territory = Some(i);
+          Command::Fortify | Command::Grow | Command::Abandon { retreat_to: None } => {}
+        }
+      }
+      GameAction::Resign => {}
+      GameAction::Donate { to, gold, research } => {
+        if gold < 0 || research < 0 {
+          bail!("Donations can't be negative");
+        }
+        if player.gold < gold || player.research < research {
+          bail!("Not enough gold or research to donate");
+        }
+        match self.player_states.get(to) {
+          None => bail!("Recipient not found"),
+          Some(_) if to == player_index => bail!("Can't donate to yourself"),
+          Some(recipient) if !recipient.is_alive => bail!("Recipient already dead"),
+          Some(_) if !self.are_allied(player_index, to) => bail!("Can only donate to allies"),
+          Some(recipient)
+            if recipient.gold.checked_add(gold).is_none()
+              || recipient.research.checked_add(research).is_none() =>
+          {
+            bail!("Recipient can't hold that much")
+          }
+          Some(_) => {}
         }
       }
     }
-    // Now we actually move the units.
-    for (target_terr_index, incoming_entry) in best_incoming.iter().enumerate() {
-      if let Some(source_terr_index) = incoming_entry.source_territory {
-        let contents = self.territories[source_terr_index].contents;
-        self.territories[target_terr_index].contents = contents;
-        self.territories[source_terr_index].contents = None;
-        animation_events.push(AnimationEvent::Movement {
-          render_info_from: self.territories[source_terr_index].render_info,
-          render_info_to:   self.territories[target_terr_index].render_info,
-          // FIXME: Shouldn't be 0.
-          amount:           0,
-        });
+
+    Ok(player_index)
+  }
+
+  /// Sets each player's upgrade levels from `levels`, which must have one entry per player.
+  pub fn apply_starting_levels(&mut self, levels: &[PlayerStartLevels]) -> Result<(), Error> {
+    if levels.len() != self.player_states.len() {
+      bail!(
+        "Got starting levels for {} players, but there are {}",
+        levels.len(),
+        self.player_states.len()
+      );
+    }
+    for (player, levels) in self.player_states.iter_mut().zip(levels) {
+      player.defense_level = levels.defense_level;
+      player.attack_level = levels.attack_level;
+      player.vision_level = levels.vision_level;
+      player.growth_level = levels.growth_level;
+    }
+    Ok(())
+  }
+
+  /// Forcibly removes a player, say for breaking the rules. Unlike a resignation, which leaves the
+  /// player's stacks on the board, this also clears every territory they hold.
+  pub fn eliminate_player(&mut self, player: PlayerIndex) -> Result<(), Error> {
+    let player_state = match self.player_states.get_mut(player) {
+      Some(player_state) => player_state,
+      None => bail!("Player not found"),
+    };
+    if player_state.is_alive {
+      player_state.is_alive = false;
+      self.game_events.push(GameEvent::PlayerEliminated { player });
+    }
+    for terr in &mut self.territories {
+      if matches!(terr.contents, Some((owner, _)) if owner == player) {
+        terr.contents = None;
+        terr.command = Command::Fortify;
+      }
+    }
+    Ok(())
+  }
+
+  //pub fn sample_win_rate(&mut self, half_atk: i32, half_def: i32) -> bool {
+  //
+  //}
+
+  /// How many units the territory's stack gains from a `Grow` order this turn, after `max_units`.
+  fn growth_of(&self, terr: &Territory) -> i32 {
+    match (terr.command, terr.contents) {
+      (Command::Grow, Some((owner, units))) => {
+        let grown = units + GROWTH_PER_TURN + self.player_states[owner].growth_level.max(0);
+        let grown = self.config.max_units.map_or(grown, |max_units| grown.min(max_units));
+        (grown - units).max(0)
+      }
+      _ => 0,
+    }
+  }
+
+  /// Adds units to every stack ordered to `Grow`, unless there's a famine.
+  fn apply_growth(&mut self) {
+    if self.world_event == Some(WorldEvent::Famine) {
+      return;
+    }
+    for i in 0..self.territories.len() {
+      let growth = self.growth_of(&self.territories[i]);
+      if let Some((_, units)) = &mut self.territories[i].contents {
+        *units += growth;
+      }
+    }
+  }
+
+  /// Decides whether a `WorldEvent` strikes this turn, and which.
+  fn roll_world_event(&mut self) -> Option<WorldEvent> {
+    if self.config.world_event_chance == 0
+      || self.rng.generate() % 100 >= self.config.world_event_chance
+    {
+      return None;
+    }
+    match self.rng.generate() % 2 {
+      0 => Some(WorldEvent::Storm),
+      _ => Some(WorldEvent::Famine),
+    }
+  }
+
+  /// Hands out `catch_up` reinforcements to everyone trailing the leader, one unit at a time onto
+  /// randomly chosen territories they hold. The further behind a player is, the more they get, up
+  /// to `max_units_per_turn`. Returns how many units each territory received.
+  pub fn grant_catch_up(&mut self) -> HashMap<TerritoryIndex, i32> {
+    let mut granted = HashMap::new();
+    let catch_up = match &self.config.catch_up {
+      Some(catch_up) => catch_up.clone(),
+      None => return granted,
+    };
+    let scores = self.scoreboard();
+    let leader_territories = scores.iter().map(|score| score.territories).max().unwrap_or(0);
+    for score in scores {
+      if !score.is_alive
+        || score.territories == 0
+        || score.territories * 100 >= leader_territories * catch_up.threshold_percent
+      {
+        continue;
+      }
+      let owned: Vec<TerritoryIndex> = (0..self.territories.len())
+        .filter(
+          |&i| matches!(self.territories[i].contents, Some((owner, _)) if owner == score.player),
+        )
+        .collect();
+      // One unit for every territory of deficit, so a player just past the threshold gets a trickle.
+      let deficit = (leader_territories - score.territories) as i32;
+      for _ in 0..deficit.min(catch_up.max_units_per_turn) {
+        let terr = owned[(self.rng.generate() % owned.len() as u64) as usize];
+        if let Some((_, units)) = &mut self.territories[terr].contents {
+          *units += 1;
+        }
+        *granted.entry(terr).or_insert(0) += 1;
+      }
+    }
+    granted
+  }
+
+  /// How many half points actually get rolled for a side bringing `half_points`, after
+  /// `combat_soft_cap`.
+  fn effective_half_points(&self, half_points: i32) -> i32 {
+    match self.config.combat_soft_cap {
+      Some(soft_cap) if half_points > soft_cap => {
+        soft_cap + ((half_points - soft_cap) as f64).sqrt() as i32
+      }
+      _ => half_points,
+    }
+  }
+
+  /// How much the defender of `terr` adds to their combat total for veterancy.
+  fn veterancy_bonus(&self, terr: &Territory) -> u64 {
+    (terr.veterancy * self.config.veterancy_bonus).max(0) as u64
+  }
+
+  /// Rolls one combat and returns whether the attacker won. `defense_bonus` is added to the
+  /// defender's total after rolling. Ties go to the defender.
+  fn roll_combat(
+    &mut self,
+    half_defense_points: i32,
+    half_attack_points: i32,
+    defense_bonus: u64,
+  ) -> bool {
+    let half_defense_points = self.effective_half_points(half_defense_points);
+    let half_attack_points = self.effective_half_points(half_attack_points);
+    let (defense_sum, attack_sum) = match self.config.combat_mode {
+      CombatMode::Random => {
+        let mut defense_sum = 0;
+        for _ in 0..half_defense_points {
+          defense_sum += self.rng.generate() % self.config.dice_sides;
+        }
+        let mut attack_sum = 0;
+        for _ in 0..half_attack_points {
+          let roll = self.rng.generate() % self.config.dice_sides;
+          attack_sum += match self.world_event {
+            Some(WorldEvent::Storm) => roll.saturating_sub(1),
+            _ => roll,
+          };
+        }
+        (defense_sum, attack_sum)
+      }
+      CombatMode::Deterministic => (
+        half_defense_points.max(0) as u64,
+        half_attack_points.max(0) as u64,
+      ),
+    };
+    attack_sum > defense_sum + defense_bonus
+  }
+
+  /// Returns the terms of the territory's half defense points, or `None` if it's empty.
+  fn defense_terms(&self, terr: &Territory) -> Option<DefenseTerms> {
+    let (owner, units) = terr.contents?;
+    Some(DefenseTerms {
+      units:         match terr.command {
+        Command::Attack { .. } | Command::Assault { .. } => {
+          self.config.attacking_half_defense_per_unit * units
+        }
+        _ => 2 * units,
+      },
+      sort:          {
+        let effects = self.config.terrain.effects(terr.sort);
+        effects.defense_bonus + effects.defense_per_unit * units
+      },
+      fortify:       match terr.command {
+        Command::Fortify => 2,
+        _ => 0,
+      },
+      defense_level: 2 * self.player_states[owner].defense_level,
+    })
+  }
+
+  /// The half points the stack on `terr` attacks with: one per unit, plus two per attack level of
+  /// its owner, mirroring `defense_level` on defense.
+  fn half_attack_points(&self, terr: &Territory) -> i32 {
+    terr.contents.map_or(0, |(owner, units)| {
+      units + 2 * self.player_states[owner].attack_level
+    })
+  }
+
+  /// Returns every territory whose current orders send its units at `terr`, with their units: first
+  /// the allies, who add to its defense, then everyone else, who attack it. Orders against
+  /// non-adjacent targets are left out, since `step_time` drops them.
+  fn orders_against(&self, terr: TerritoryIndex) -> (Vec<SourceUnits>, Vec<SourceUnits>) {
+    let territory = &self.territories[terr];
+    let mut supporters = vec![];
+    let mut attackers = vec![];
+    for (i, source) in self.territories.iter().enumerate() {
+      let (owner, units) = match source.contents {
+        Some(pair) => pair,
+        None => continue,
+      };
+      match source.command.attack_target() {
+        Some(target) if target == terr && source.adjacent.contains(&target) => {
+          match territory.contents {
+            Some((target_owner, _)) if self.are_allied(target_owner, owner) => {
+              supporters.push((i, units))
+            }
+            _ => attackers.push((i, units)),
+          }
+        }
+        _ => {}
+      }
+    }
+    (supporters, attackers)
+  }
+
+  /// Returns every territory currently ordered to attack `terr` from outside its owner's alliance.
+  pub fn attackers_of(&self, terr: TerritoryIndex) -> Vec<TerritoryIndex> {
+    self.orders_against(terr).1.into_iter().map(|(source, _)| source).collect()
+  }
+
+  /// Explains how the combat at `terr` would go if the turn resolved with the current commands.
+  /// Nothing is rolled, so the game's RNG is left untouched.
+  pub fn explain_combat(&self, terr: TerritoryIndex) -> CombatExplanation {
+    let defense_terms = self.defense_terms(&self.territories[terr]);
+    let (supporters, attackers) = self.orders_against(terr);
+    let half_defense_points = defense_terms.map_or(0, |terms| terms.total())
+      + supporters.iter().map(|&(_, units)| units).sum::<i32>();
+    let incoming_half_attack_points = attackers
+      .iter()
+      .map(|&(source, _)| self.half_attack_points(&self.territories[source]))
+      .sum::<i32>();
+    let defense_bonus = self.veterancy_bonus(&self.territories[terr]);
+    let attack_win_probability = self.attack_win_probability(
+      half_defense_points,
+      incoming_half_attack_points,
+      defense_bonus,
+    );
+    CombatExplanation {
+      territory: terr,
+      defense_terms,
+      supporters,
+      attackers,
+      half_defense_points,
+      incoming_half_attack_points,
+      attack_win_probability,
+    }
+  }
+
+  /// The chance that `half_attack_points` beat `half_defense_points` (plus `defense_bonus`) in a
+  /// single combat, computed exactly rather than rolled.
+  fn attack_win_probability(
+    &self,
+    half_defense_points: i32,
+    half_attack_points: i32,
+    defense_bonus: u64,
+  ) -> f64 {
+    let effective_defense = self.effective_half_points(half_defense_points);
+    let effective_attack = self.effective_half_points(half_attack_points);
+    let defense_bonus = defense_bonus as usize;
+    match self.config.combat_mode {
+      CombatMode::Random => {
+        let defense = dice_sum_distribution(effective_defense, self.config.dice_sides);
+        let attack = dice_sum_distribution(effective_attack, self.config.dice_sides);
+        // Sum over every attack total the chance that the defense rolled strictly less.
+        let mut defense_below = 0.0;
+        let mut probability = 0.0;
+        for (attack_sum, attack_chance) in attack.iter().enumerate() {
+          probability += attack_chance * defense_below;
+          let defense_roll = attack_sum.checked_sub(defense_bonus);
+          defense_below += defense_roll.and_then(|roll| defense.get(roll)).copied().unwrap_or(0.0);
+        }
+        probability
+      }
+      CombatMode::Deterministic => {
+        if effective_attack.max(0) as usize > effective_defense.max(0) as usize + defense_bonus {
+          1.0
+        } else {
+          0.0
+        }
+      }
+    }
+  }
+
+  /// Recommends attacks for `player` as `(source, target)` pairs: for each adjacent enemy stack,
+  /// weakest first, the fewest of the player's biggest neighboring stacks that win with at least
+  /// `RECOMMENDED_WIN_PROBABILITY`. Each source is only ever sent at one target, and targets that
+  /// can't be beaten with the sources left over are skipped.
+  ///
+  /// Enemy orders are secret, so every target is assumed to be fortifying without support.
+  pub fn best_attack_targets(&self, player: PlayerIndex) -> Vec<(TerritoryIndex, TerritoryIndex)> {
+    let mut targets: Vec<(i32, TerritoryIndex)> = self
+      .territories
+      .iter()
+      .enumerate()
+      .filter_map(|(i, terr)| match terr.contents {
+        Some((owner, _)) if !self.are_allied(owner, player) => {
+          let mut fortified = terr.clone();
+          fortified.command = Command::Fortify;
+          let half_defense_points = self.defense_terms(&fortified)?.total();
+          Some((half_defense_points, i))
+        }
+        _ => None,
+      })
+      .collect();
+    targets.sort();
+    let mut used = vec![false; self.territories.len()];
+    let mut recommended = vec![];
+    for (half_defense_points, target) in targets {
+      let defense_bonus = self.veterancy_bonus(&self.territories[target]);
+      let mut sources: Vec<(i32, TerritoryIndex)> = self.territories[target]
+        .adjacent
+        .iter()
+        .filter(|&&adj| !used[adj] && self.territories[adj].adjacent.contains(&target))
+        .filter_map(|&adj| match self.territories[adj].contents {
+          Some((owner, units)) if owner == player => Some((units, adj)),
+          _ => None,
+        })
+        .collect();
+      sources.sort_by_key(|&(units, adj)| (std::cmp::Reverse(units), adj));
+      let mut half_attack_points = 0;
+      for (count, &(_, source)) in sources.iter().enumerate() {
+        half_attack_points += self.half_attack_points(&self.territories[source]);
+        let probability =
+          self.attack_win_probability(half_defense_points, half_attack_points, defense_bonus);
+        if probability >= RECOMMENDED_WIN_PROBABILITY {
+          for &(_, source) in &sources[..=count] {
+            used[source] = true;
+            recommended.push((source, target));
+          }
+          break;
+        }
       }
     }
+    recommended
+  }
+
+  /// Estimates, for each territory `player` holds, the chance that they won't hold it after the
+  /// coming turn, by playing the turn out `trials` times on copies of the game. Each trial rolls
+  /// from its own fork of the RNG, so the real game's rolls aren't disturbed or revealed.
+  pub fn predicted_losses(&self, player: PlayerIndex, trials: u32) -> HashMap<TerritoryIndex, f64> {
+    let owned: Vec<TerritoryIndex> = (0..self.territories.len())
+      .filter(|&i| matches!(self.territories[i].contents, Some((owner, _)) if owner == player))
+      .collect();
+    let mut losses: HashMap<TerritoryIndex, u32> = owned.iter().map(|&i| (i, 0)).collect();
+    let mut completed_trials = 0;
+    for trial in 0..trials {
+      let mut trial_state = self.clone();
+      trial_state.set_rng(self.rng.fork(trial as u64));
+      if trial_state.step_time().is_err() {
+        continue;
+      }
+      completed_trials += 1;
+      for &i in &owned {
+        if !matches!(trial_state.territories[i].contents, Some((owner, _)) if owner == player) {
+          *losses.entry(i).or_default() += 1;
+        }
+      }
+    }
+    losses
+      .into_iter()
+      .map(|(i, lost)| (i, lost as f64 / completed_trials.max(1) as f64))
+      .collect()
+  }
+
+  /// Returns how much gold each Gold territory pays out on the current turn.
+  pub fn gold_income_per_territory(&self) -> i32 {
+    let decay = match &self.config.gold_decay {
+      Some(decay) => decay,
+      None => return 1,
+    };
+    if self.turn < decay.start_turn {
+      return decay.initial_income.max(0);
+    }
+    let steps = (self.turn - decay.start_turn) / decay.turns_per_step.max(1) + 1;
+    let steps = i32::try_from(steps).unwrap_or(i32::MAX);
+    decay.initial_income.saturating_sub(steps).max(0)
+  }
+
+  /// Returns every player's current standing, in player order.
+  pub fn scoreboard(&self) -> Vec<PlayerScore> {
+    let mut scores: Vec<PlayerScore> = self
+      .player_states
+      .iter()
+      .enumerate()
+      .map(|(player, player_state)| PlayerScore {
+        player,
+        is_alive: player_state.is_alive,
+        territories: 0,
+        units: 0,
+        gold: player_state.gold,
+        research: player_state.research,
+      })
+      .collect();
+    for terr in &self.territories {
+      if let Some((owner, units)) = terr.contents {
+        scores[owner].territories += 1;
+        scores[owner].units += units;
+      }
+    }
+    scores
+  }
+
+  /// Estimates what the player will earn and grow this turn from the board as it is now. `step_time`
+  /// pays out on the board after combat and movement, so this is off whenever a Gold or Lab
+  /// territory changes hands, and a famine, which isn't rolled until the turn resolves, wipes it out.
+  pub fn income_preview(&self, player: PlayerIndex) -> IncomePreview {
+    let mut income = IncomePreview {
+      gold:     0,
+      research: 0,
+      growth:   0,
+    };
+    let gold_income = self.gold_income_per_territory();
+    for terr in &self.territories {
+      if matches!(terr.contents, Some((owner, _)) if owner == player) {
+        income.growth += self.growth_of(terr);
+        if self.config.economy_enabled {
+          let effects = self.config.terrain.effects(terr.sort);
+          income.gold += effects.gold_yield * gold_income;
+          income.research += effects.research_yield;
+        }
+      }
+    }
+    income
+  }
+
+  /// Checks that every index stored on the board is in range, so `step_time` can index freely.
+  fn check_board(&self) -> Result<(), Error> {
+    if self.config.dice_sides == 0 {
+      bail!("Dice must have at least one side");
+    }
+    for (i, terr) in self.territories.iter().enumerate() {
+      if let Some(adj) = terr.adjacent.iter().find(|&&adj| adj >= self.territories.len()) {
+        bail!(
+          "Territory {} lists adjacency {} which is out of range [0, {})",
+          i,
+          adj,
+          self.territories.len()
+        );
+      }
+      if let Some((owner, _)) = terr.contents {
+        if owner >= self.player_states.len() {
+          bail!(
+            "Territory {} is owned by player {} which is out of range [0, {})",
+            i,
+            owner,
+            self.player_states.len()
+          );
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks that the board makes sense as a map to start a game on, which is stricter than what
+  /// `step_time` needs: adjacency must be symmetric with no self-references or repeated neighbors,
+  /// and every player must start with a territory.
+  pub fn validate(&self) -> Result<(), Error> {
+    self.check_board()?;
+    if self.player_states.is_empty() {
+      bail!("Map has no players");
+    }
+    for (i, terr) in self.territories.iter().enumerate() {
+      if terr.adjacent.contains(&i) {
+        bail!("Territory {} is adjacent to itself", i);
+      }
+      if let Some((j, &adj)) =
+        terr.adjacent.iter().enumerate().find(|&(j, adj)| terr.adjacent[..j].contains(adj))
+      {
+        bail!(
+          "Territory {} lists neighbor {} more than once (again at position {})",
+          i,
+          adj,
+          j
+        );
+      }
+      if let Some((_, units)) = terr.contents {
+        if units <= 0 {
+          bail!(
+            "Territory {} starts with {} units, but needs at least 1",
+            i,
+            units
+          );
+        }
+      }
+      if let Some(&adj) =
+        terr.adjacent.iter().find(|&&adj| !self.territories[adj].adjacent.contains(&i))
+      {
+        bail!(
+          "Territory {} is adjacent to territory {}, but not the other way around",
+          i,
+          adj
+        );
+      }
+    }
+    for player in 0..self.player_states.len() {
+      if !self
+        .territories
+        .iter()
+        .any(|terr| matches!(terr.contents, Some((owner, _)) if owner == player))
+      {
+        bail!("Player {} has no starting territory", player);
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks, if `check_overlaps` is set, that no two territories share a `render_info`, which would
+  /// draw them on top of each other, and, if `bounds` is given, that every territory falls within it.
+  pub fn validate_render_info(
+    &self,
+    check_overlaps: bool,
+    bounds: Option<RenderBounds>,
+  ) -> Result<(), Error> {
+    let mut seen: HashMap<(i32, i32), TerritoryIndex> = HashMap::new();
+    for (i, terr) in self.territories.iter().enumerate() {
+      if let Some(other) = seen.insert(terr.render_info, i).filter(|_| check_overlaps) {
+        bail!(
+          "Territories {} and {} are both drawn at {:?}",
+          other,
+          i,
+          terr.render_info
+        );
+      }
+      if let Some(bounds) = bounds {
+        let (x, y) = terr.render_info;
+        if x < bounds.min.0 || y < bounds.min.1 || x > bounds.max.0 || y > bounds.max.1 {
+          bail!(
+            "Territory {} is drawn at {:?}, outside of {:?} to {:?}",
+            i,
+            terr.render_info,
+            bounds.min,
+            bounds.max
+          );
+        }
+      }
+    }
+    Ok(())
+  }
+
+  pub fn outcome(&self) -> GameOutcome {
+    let survivors: Vec<PlayerIndex> = (0..self.player_states.len())
+      .filter(|&player| self.player_states[player].is_alive)
+      .collect();
+    let shared_alliance = survivors
+      .first()
+      .and_then(|&first| self.player_states[first].alliance_id)
+      .filter(|_| survivors.iter().all(|&player| self.are_allied(survivors[0], player)));
+    match (survivors.as_slice(), shared_alliance) {
+      ([], _) => GameOutcome::Draw,
+      (&[winner], _) => GameOutcome::Victory { winner },
+      (_, Some(alliance_id)) => GameOutcome::AllianceVictory { alliance_id },
+      _ => match self.victory_condition_winner() {
+        Some(winner) => GameOutcome::Victory { winner },
+        None if self.config.max_turns.is_some_and(|max_turns| self.turn >= max_turns) => {
+          GameOutcome::Draw
+        }
+        None => GameOutcome::Ongoing,
+      },
+    }
+  }
+
+  /// Returns whoever has met the `victory_condition`, if anyone. If several players have at once,
+  /// the one furthest ahead wins, and the lowest index breaks ties.
+  fn victory_condition_winner(&self) -> Option<PlayerIndex> {
+    let alive = (0..self.player_states.len()).filter(|&player| self.player_states[player].is_alive);
+    let streak = |player: PlayerIndex| self.victory_streaks.get(player).copied().unwrap_or(0);
+    match self.config.victory_condition {
+      VictoryCondition::LastStanding => None,
+      VictoryCondition::Economic { gold } => alive
+        .filter(|&player| self.player_states[player].gold >= gold)
+        .max_by_key(|&player| (self.player_states[player].gold, std::cmp::Reverse(player))),
+      VictoryCondition::Domination { turns, .. }
+      | VictoryCondition::KingOfTheHill { turns, .. } => alive
+        .filter(|&player| streak(player) >= turns.max(1))
+        .max_by_key(|&player| (streak(player), std::cmp::Reverse(player))),
+    }
+  }
+
+  /// Extends the streak of every player meeting the `victory_condition` at the end of this turn,
+  /// and resets everyone else's.
+  fn update_victory_streaks(&mut self) {
+    let scores = self.scoreboard();
+    self.victory_streaks.resize(self.player_states.len(), 0);
+    for (player, streak) in self.victory_streaks.iter_mut().enumerate() {
+      let meets_condition = match self.config.victory_condition {
+        VictoryCondition::Domination { percent, .. } => {
+          scores[player].territories * 100 >= self.territories.len() * percent
+        }
+        VictoryCondition::KingOfTheHill { territory, .. } => matches!(
+          self.territories.get(territory).and_then(|terr| terr.contents),
+          Some((owner, _)) if owner == player
+        ),
+        VictoryCondition::LastStanding | VictoryCondition::Economic { .. } => false,
+      };
+      *streak = match meets_condition {
+        true => *streak + 1,
+        false => 0,
+      };
+    }
+  }
+
+  /// Resolves one turn. Combat is two-phase: every territory's defense and incoming attack points
+  /// are computed from the board as it stood at the start of combat, and only once every combat has
+  /// been decided are the losers cleared. So in `CombatMode::Deterministic` who wins each combat
+  /// doesn't depend on how the territories happen to be numbered (though movement ties still go to
+  /// the lowest index), and in `CombatMode::Random` numbering only decides which dice land where.
+  /// Mutual attacks get no special treatment, so they can leave both territories empty.
+  pub fn step_time(&mut self) -> Result<StepReport, Error> {
+    // Orders belong to the stack that was given them, so any left on an empty territory are stale,
+    // and mustn't be carried out by whoever holds it next. This goes before the scenario, so that
+    // orders it gives the stacks it places survive.
+    for terr in &mut self.territories {
+      if terr.contents.is_none() {
+        terr.command = Command::Fortify;
+      }
+    }
+    // The script gets the state to itself, so it has to be taken out while it runs. It goes before
+    // `check_board`, so that anything it breaks is caught.
+    if let Some(mut scenario) = self.scenario.take() {
+      scenario.on_turn(self, self.turn);
+      self.scenario = Some(scenario);
+    }
+    self.check_board()?;
+    // Catch whoever holds each territory now, including stacks the scenario or a map edit placed.
+    for terr in &mut self.territories {
+      if let Some((owner, _)) = terr.contents {
+        terr.last_owner = Some(owner);
+      }
+    }
+    self.world_event = self.roll_world_event();
+    if let Some(event) = self.world_event {
+      self.game_events.push(GameEvent::WorldEvent {
+        event,
+        turn: self.turn,
+      });
+    }
+    let mut warnings = vec![];
+    let mut animation_events = vec![];
+    let mut combat_stats = CombatStats::default();
+    // Orders that no longer point at a neighbor (say, after a map edit) are dropped.
+    for (i, terr) in self.territories.iter_mut().enumerate() {
+      if let Some(target) = terr.command.attack_target() {
+        if !terr.adjacent.contains(&target) {
+          warnings.push(format!(
+            "Territory {} was ordered to attack non-adjacent territory {}; fortifying instead",
+            i, target
+          ));
+          terr.command = Command::Fortify;
+        }
+      }
+    }
+
+    // Abandoned territories empty out before any fighting. Retreats are worked out against the
+    // board as it was, so a retreat into a territory that's also being abandoned just disbands.
+    let mut retreats = vec![];
+    for (i, terr) in self.territories.iter().enumerate() {
+      let (owner, units) = match (terr.command, terr.contents) {
+        (Command::Abandon { .. }, Some(pair)) => pair,
+        _ => continue,
+      };
+      if let Command::Abandon {
+        retreat_to: Some(retreat_to),
+      } = terr.command
+      {
+        // Check adjacency first, since that also guarantees the index is in range.
+        let still_held = terr.adjacent.contains(&retreat_to)
+          && matches!(self.territories[retreat_to].contents, Some((retreat_owner, _)) if retreat_owner == owner);
+        if !still_held {
+          warnings.push(format!(
+            "Territory {} can't retreat into territory {}; disbanding instead",
+            i, retreat_to
+          ));
+        } else if !matches!(
+          self.territories[retreat_to].command,
+          Command::Abandon { .. }
+        ) {
+          retreats.push((i, retreat_to, units / 2));
+        }
+      }
+    }
+    for terr in &mut self.territories {
+      if let Command::Abandon { .. } = terr.command {
+        terr.contents = None;
+        terr.command = Command::Fortify;
+      }
+    }
+    for (from, to, units) in retreats {
+      if let Some((_, held)) = &mut self.territories[to].contents {
+        *held += units;
+      }
+      animation_events.push(AnimationEvent::Movement {
+        render_info_from: self.territories[from].render_info,
+        render_info_to:   self.territories[to].render_info,
+        amount:           units,
+      });
+    }
+    if self.config.growth_timing == GrowthTiming::Before {
+      self.apply_growth();
+    }
+
+    // Each territory's defense points are:
+    // - The number of units in the territory, scaled down by the config if it's attacking.
+    // - An adjustment for the territory sort (-1 for swamp, +1 for forest).
+    // - An adjustment for fortification (+1).
+    // - Any friendly units "attacking" the territory sum their units to the defense points.
+    // - The owner's defense level is added to the defense points.
+    let mut half_defense_points: Vec<i32> = self
+      .territories
+      .iter()
+      .map(|terr| self.defense_terms(terr).map_or(0, |terms| terms.total()))
+      .collect();
+    // Each territory's incoming attack points is simply the sum of the units attacking it,
+    // plus twice the attacker's attack level for each attacking territory.
+    let mut incoming_half_attack_points: Vec<i32> = vec![0; self.territories.len()];
+    let mut attacker_render_infos: Vec<Vec<(i32, i32)>> = vec![vec![]; self.territories.len()];
+    let mut recent_attacks = vec![];
+    for (i, terr) in self.territories.iter().enumerate() {
+      let (owner, units) = match terr.contents {
+        Some(pair) => pair,
+        None => continue,
+      };
+      if let Some(target) = terr.command.attack_target() {
+        // Check who owns the target territory.
+        if self.territories[target]
+          .contents
+          .map(|(target_owner, _)| self.are_allied(target_owner, owner))
+          .unwrap_or(false)
+        {
+          half_defense_points[target] += units;
+        } else {
+          incoming_half_attack_points[target] += self.half_attack_points(terr);
+          attacker_render_infos[target].push(terr.render_info);
+          combat_stats.attacks += 1;
+          if let Some((target_owner, _)) = self.territories[target].contents {
+            recent_attacks.push((i, target_owner));
+          }
+        }
+      }
+    }
+    self.recent_attacks = recent_attacks;
+    // Decide every combat from the points above before touching the board, so that no territory's
+    // result can leak into another's inputs. Only the order the dice are drawn in follows indices.
+    // In particular, two territories attacking each other fight two independent combats, and if both
+    // defenders lose then both territories end up empty: the stacks never swap places, since
+    // stacks that died can't move.
+    let mut is_defeated = vec![false; self.territories.len()];
+    for i in 0..self.territories.len() {
+      let defense_bonus = self.veterancy_bonus(&self.territories[i]);
+      is_defeated[i] = self.roll_combat(
+        half_defense_points[i],
+        incoming_half_attack_points[i],
+        defense_bonus,
+      );
+    }
+    // Remember who lost each territory, to credit the capture once someone moves in.
+    let lost_by: Vec<Option<PlayerIndex>> = self
+      .territories
+      .iter()
+      .zip(&is_defeated)
+      .map(|(terr, &is_defeated)| match is_defeated {
+        true => terr.contents.map(|(owner, _)| owner),
+        false => None,
+      })
+      .collect();
+    // Then have all dying territories lose their units.
+    for ((terr, is_defeated), attackers) in
+      self.territories.iter_mut().zip(is_defeated).zip(attacker_render_infos)
+    {
+      if let (Some((_, units)), false) = (terr.contents, attackers.is_empty()) {
+        if is_defeated {
+          combat_stats.captures += 1;
+          combat_stats.units_lost += units.max(0) as u64;
+        } else {
+          combat_stats.holds += 1;
+          terr.veterancy += 1;
+        }
+      }
+      if is_defeated {
+        terr.contents = None;
+        terr.command = Command::Fortify;
+        animation_events.push(AnimationEvent::Death {
+          render_info: terr.render_info,
+          // FIXME: Shouldn't be 0.
+          amount: 0, //terr.units,
+          attackers,
+        });
+      }
+    }
+    // For each empty territory, gather the stacks that want to move into it. Normally every stack
+    // competes on its own, but under `StackPolicy::Merge` each alliance's stacks band together.
+    struct Contender {
+      owner:   PlayerIndex,
+      units:   i32,
+      sources: Vec<TerritoryIndex>,
+    }
+    let mut contenders: Vec<Vec<Contender>> = (0..self.territories.len()).map(|_| vec![]).collect();
+    for (i, terr) in self.territories.iter().enumerate() {
+      let (owner, units) = match terr.contents {
+        Some(pair) => pair,
+        None => continue,
+      };
+      if let Some(target) = terr.command.attack_target() {
+        // You can only move into empty territories.
+        if self.territories[target].contents.is_some() {
+          continue;
+        }
+        let ally = match self.config.stack_policy {
+          StackPolicy::Largest => None,
+          StackPolicy::Merge => {
+            contenders[target].iter_mut().find(|c| self.are_allied(c.owner, owner))
+          }
+        };
+        match ally {
+          Some(ally) => {
+            ally.units += units;
+            ally.sources.push(i);
+          }
+          None => contenders[target].push(Contender {
+            owner,
+            units,
+            sources: vec![i],
+          }),
+        }
+      }
+    }
+    // The contender with the most units moves in, and the rest stay put.
+    for (target, mut target_contenders) in contenders.into_iter().enumerate() {
+      if self.config.contested_moves && target_contenders.len() >= 2 {
+        // The two biggest contenders fight it out, with the bigger one defending. Both sides are on
+        // the move, so each unit is worth one half point either way. The sort is stable, so among
+        // equals the lowest index counts as bigger. The loser's stacks are wiped out, and the winner
+        // moves in minus as many units as the loser brought (but always at least one).
+        target_contenders.sort_by_key(|contender| std::cmp::Reverse(contender.units));
+        target_contenders.truncate(2);
+        let (smaller, bigger) = match (target_contenders.pop(), target_contenders.pop()) {
+          (Some(smaller), Some(bigger)) => (smaller, bigger),
+          _ => continue,
+        };
+        let (mut winner, loser) = match self.roll_combat(bigger.units, smaller.units, 0) {
+          true => (smaller, bigger),
+          false => (bigger, smaller),
+        };
+        winner.units = (winner.units - loser.units).max(1);
+        let attackers: Vec<(i32, i32)> =
+          winner.sources.iter().map(|&source| self.territories[source].render_info).collect();
+        for source in loser.sources {
+          self.territories[source].contents = None;
+          self.territories[source].command = Command::Fortify;
+          animation_events.push(AnimationEvent::Death {
+            render_info: self.territories[source].render_info,
+            // FIXME: Shouldn't be 0.
+            amount:      0,
+            attackers:   attackers.clone(),
+          });
+        }
+        target_contenders.push(winner);
+      }
+      let mut best: Option<Contender> = None;
+      let mut competitor_count = 0;
+      for contender in target_contenders {
+        let best_units = best.as_ref().map_or(-1, |best| best.units);
+        let is_new_best = match contender.units.cmp(&best_units) {
+          Ordering::Greater => {
+            competitor_count = 1;
+            true
+          }
+          Ordering::Equal => {
+            competitor_count += 1;
+            match self.config.combat_mode {
+              // If it's a tie, we have a 1/competitor_count chance of being the new best.
+              CombatMode::Random => self.rng.generate() % competitor_count == 0,
+              // Contenders are visited in index order, so keeping the incumbent favors the lowest index.
+              CombatMode::Deterministic => false,
+            }
+          }
+          Ordering::Less => false,
+        };
+        if is_new_best {
+          best = Some(contender);
+        }
+      }
+      if let Some(winner) = best {
+        let units = match self.config.move_mode {
+          MoveMode::March => winner.units,
+          MoveMode::Scorch => winner.units.min(SCORCH_GARRISON),
+        };
+        self.territories[target].contents = Some((winner.owner, units));
+        // Whatever order was left on the territory belonged to its old owner, so the new owner
+        // starts out fortifying rather than carrying it out.
+        self.territories[target].command = Command::Fortify;
+        self.territories[target].veterancy = 0;
+        if let Some(loser) = lost_by[target] {
+          self.territories[target].previous_owner = Some(loser);
+        }
+        // Moving back into a territory you were the last to hold doesn't change hands.
+        if self.territories[target].last_owner != Some(winner.owner) {
+          self.territories[target].capture_count += 1;
+          self.player_states[winner.owner].gold += self.config.capture_bonus_gold;
+        }
+        self.territories[target].last_owner = Some(winner.owner);
+        self.game_events.push(GameEvent::TerritoryCaptured {
+          by:        winner.owner,
+          from:      lost_by[target],
+          territory: target,
+        });
+        for source in winner.sources {
+          self.territories[source].contents = None;
+          self.territories[source].command = Command::Fortify;
+          animation_events.push(AnimationEvent::Movement {
+            render_info_from: self.territories[source].render_info,
+            render_info_to:   self.territories[target].render_info,
+            // FIXME: Shouldn't be 0.
+            amount:           0,
+          });
+        }
+      }
+    }
+    if self.config.growth_timing == GrowthTiming::After {
+      self.apply_growth();
+    }
+    // Reinforcements land before the cap, so they can't push a stack past it.
+    self.grant_catch_up();
+    // Merged stacks can overshoot the cap, so clamp everything back down.
+    if let Some(max_units) = self.config.max_units {
+      for terr in &mut self.territories {
+        if let Some((_, units)) = &mut terr.contents {
+          *units = (*units).min(max_units);
+        }
+      }
+    }
+    // A stack with no units left isn't a stack, and mustn't hold the territory or block movement.
+    for terr in &mut self.territories {
+      if matches!(terr.contents, Some((_, units)) if units <= 0) {
+        terr.contents = None;
+        terr.command = Command::Fortify;
+      }
+    }
+    // Assaults use up a wave each turn, and end once they run out of waves, their territory is lost
+    // or moves out, or the target falls.
+    for i in 0..self.territories.len() {
+      let (target, waves) = match self.territories[i].command {
+        Command::Assault { target, waves } => (target, waves),
+        _ => continue,
+      };
+      let is_over = match (
+        self.territories[i].contents,
+        self.territories[target].contents,
+      ) {
+        (None, _) => true,
+        (Some((owner, _)), Some((target_owner, _))) if self.are_allied(owner, target_owner) => true,
+        _ => lost_by[target].is_some() || waves <= 1,
+      };
+      self.territories[i].command = match is_over {
+        true => Command::Fortify,
+        false => Command::Assault {
+          target,
+          waves: waves - 1,
+        },
+      };
+    }
+    // Pay out whatever the board is now worth to each player, unless there's a famine.
+    if self.world_event != Some(WorldEvent::Famine) {
+      for player in 0..self.player_states.len() {
+        let income = self.income_preview(player);
+        self.player_states[player].gold += income.gold;
+        self.player_states[player].research += income.research;
+      }
+    }
+    // Anyone left without a single territory is eliminated.
+    let mut holds_territory = vec![false; self.player_states.len()];
+    for terr in &self.territories {
+      if let Some((owner, _)) = terr.contents {
+        holds_territory[owner] = true;
+      }
+    }
+    for (player_index, (player, holds_territory)) in
+      self.player_states.iter_mut().zip(holds_territory).enumerate()
+    {
+      if !holds_territory && player.is_alive {
+        player.is_alive = false;
+        self.game_events.push(GameEvent::PlayerEliminated {
+          player: player_index,
+        });
+      }
+    }
+    // Veterancy belongs to the stack, so it's gone wherever the stack died, moved out, or disbanded.
+    for terr in &mut self.territories {
+      if terr.contents.is_none() {
+        terr.veterancy = 0;
+      }
+    }
+    if !self.config.orders_persist {
+      for terr in &mut self.territories {
+        terr.command = Command::Fortify;
+      }
+    }
+    self.update_victory_streaks();
+
+    self.turn += 1;
+    Ok(StepReport {
+      animation_events,
+      outcome: self.outcome(),
+      warnings,
+      combat_stats,
+    })
+  }
+}
+
+/// Plays out a whole turn: applies `pending` in order, then resolves combat with `step_time`.
+///
+/// Actions that fail don't stop the turn, they're just listed in the report, so one bad order can't
+/// hold up everyone else. This doesn't touch any networking, so the server only has to send out the
+/// report.
+pub fn resolve_turn(
+  state: &mut GameState,
+  pending: &[(PlayerToken, GameAction)],
+) -> Result<TurnReport, Error> {
+  let mut rejected = vec![];
+  for (player_token, action) in pending {
+    if let Err(err) = state.process_action(player_token, action.clone()) {
+      rejected.push(format!("Rejected {:?}: {}", action, err));
+    }
+  }
+  let players = 0..state.player_states.len();
+  let visible_before: Vec<Vec<bool>> =
+    players.clone().map(|player| state.visible_territories(player)).collect();
+  state.phase = GamePhase::Resolving;
+  let step = state.step_time();
+  state.phase = GamePhase::Planning;
+  let step = step?;
+  Ok(TurnReport {
+    views: players.clone().map(|player| state.player_view(Some(player))).collect(),
+    events: players
+      .map(|player| {
+        state.filter_events_for(&step.animation_events, player, &visible_before[player])
+      })
+      .collect(),
+    step,
+    rejected,
+  })
+}
+
+// `command_kind` and `GameAction::kind` are exhaustive matches, so a new variant won't compile until
+// it's listed there, and then belongs in the matching `*_keep_their_wire_kinds` test too.
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A row of land territories, each adjacent to its neighbors, holding `contents` in order. Combat
+  /// is deterministic, and player `i`'s token is `player-{i}`.
+  fn line_board(players: usize, contents: &[Option<(PlayerIndex, i32)>]) -> GameState {
+    let config = GameConfig {
+      combat_mode: CombatMode::Deterministic,
+      ..GameConfig::default()
+    };
+    let mut state = GameState::with_config(0, config);
+    for (i, &contents_here) in contents.iter().enumerate() {
+      let mut adjacent = vec![];
+      if i > 0 {
+        adjacent.push(i - 1);
+      }
+      if i + 1 < contents.len() {
+        adjacent.push(i + 1);
+      }
+      state.territories.push(Territory {
+        sort: TerritorySort::Land,
+        contents: contents_here,
+        command: Command::Fortify,
+        adjacent,
+        render_info: (i as i32, 0),
+        veterancy: 0,
+        capture_count: 0,
+        previous_owner: None,
+        last_owner: None,
+      });
+    }
+    for player in 0..players {
+      state.player_states.push(PlayerState::new(player));
+      state.player_indices_by_token.insert(token(player), player);
+    }
+    state
+  }
+
+  fn token(player: PlayerIndex) -> PlayerToken {
+    format!("player-{}", player)
+  }
+
+  /// Gives `territory` the command, as whoever holds it.
+  fn order(state: &mut GameState, territory: TerritoryIndex, command: Command) {
+    let (owner, _) = state.territories[territory].contents.expect("ordered an empty territory");
+    let action = GameAction::SetCommand { territory, command };
+    state.process_action(&token(owner), action).expect("order rejected");
+  }
+
+  #[test]
+  fn losing_a_territory_shows_the_fight_that_took_it() {
+    // Player 0 holds both ends, and player 1 wipes out the left end, which player 0 then can't see.
+    let mut state = line_board(
+      2,
+      &[Some((0, 1)), Some((1, 6)), None, None, None, Some((0, 1))],
+    );
+    order(&mut state, 1, Command::Attack { target: 0 });
+    let turn = resolve_turn(&mut state, &[]).unwrap();
+    assert!(!state.visible_territories(0)[0]);
+    let death_at_0 = |events: &[AnimationEvent]| {
+      events.iter().any(|event| {
+        matches!(
+          event,
+          AnimationEvent::Death {
+            render_info: (0, 0),
+            ..
+          }
+        )
+      })
+    };
+    assert!(death_at_0(&turn.events[0]));
+    assert!(death_at_0(&turn.events[1]));
+  }
+
+  #[test]
+  fn deaths_out_of_sight_are_filtered() {
+    let state = line_board(2, &[Some((0, 1)), None, None, None, Some((1, 1))]);
+    let events = [AnimationEvent::Death {
+      render_info: (0, 0),
+      amount:      1,
+      attackers:   vec![],
+    }];
+    let visible_before = vec![false; state.territories.len()];
+    assert_eq!(
+      state.filter_events_for(&events, 0, &visible_before).len(),
+      1
+    );
+    assert!(state.filter_events_for(&events, 1, &visible_before).is_empty());
+  }
+
+  #[test]
+  fn orders_are_closed_while_a_turn_resolves() {
+    let mut state = line_board(2, &[Some((0, 2)), None, Some((1, 2))]);
+    state.phase = GamePhase::Resolving;
+    let action = GameAction::SetCommand {
+      territory: 0,
+      command:   Command::Attack { target: 1 },
+    };
+    assert!(state.process_action(&token(0), action.clone()).is_err());
+    assert_eq!(state.territories[0].command, Command::Fortify);
+    state.phase = GamePhase::Planning;
+    state.process_action(&token(0), action).unwrap();
+    assert_eq!(state.territories[0].command, Command::Attack { target: 1 });
+  }
+
+  #[test]
+  fn orders_are_open_again_once_a_turn_resolves() {
+    let mut state = line_board(2, &[Some((0, 2)), None, Some((1, 2))]);
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.phase, GamePhase::Planning);
+    order(&mut state, 0, Command::Attack { target: 1 });
+  }
+
+  /// Plays one turn of `orders` (as attacker, target pairs) on a `line_board`, and returns what's
+  /// left on each territory.
+  fn fight(
+    contents: &[Option<(PlayerIndex, i32)>],
+    orders: &[(TerritoryIndex, TerritoryIndex)],
+  ) -> Vec<Option<(PlayerIndex, i32)>> {
+    let mut state = line_board(2, contents);
+    for &(source, target) in orders {
+      order(&mut state, source, Command::Attack { target });
+    }
+    resolve_turn(&mut state, &[]).unwrap();
+    state.territories.iter().map(|terr| terr.contents).collect()
+  }
+
+  #[test]
+  fn combat_does_not_depend_on_territory_order() {
+    let contents = [Some((0, 6)), Some((1, 1)), Some((1, 6)), Some((0, 2)), None];
+    let orders = [(0, 1), (2, 3), (3, 4)];
+    let forwards = fight(&contents, &orders);
+    // Reversing a row keeps every adjacency, so the same fights happen under the opposite indices.
+    let last = contents.len() - 1;
+    let reversed_contents: Vec<_> = contents.iter().rev().copied().collect();
+    let reversed_orders: Vec<_> =
+      orders.iter().map(|&(source, target)| (last - source, last - target)).collect();
+    let mut backwards = fight(&reversed_contents, &reversed_orders);
+    backwards.reverse();
+    assert_eq!(forwards, backwards);
+    assert_eq!(forwards[1], Some((0, 6)));
+  }
+
+  #[test]
+  fn territories_attacking_each_other_both_fall() {
+    // On swamp an attacking stack defends with fewer points than it attacks with.
+    let mut state = line_board(2, &[Some((0, 4)), Some((1, 4))]);
+    for terr in &mut state.territories {
+      terr.sort = TerritorySort::Swamp;
+    }
+    order(&mut state, 0, Command::Attack { target: 1 });
+    order(&mut state, 1, Command::Attack { target: 0 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert!(state.territories.iter().all(|terr| terr.contents.is_none()));
+  }
+
+  #[test]
+  fn attacks_must_beat_the_defense_outright() {
+    // One unit fortified on land is worth 2 + 1 + 2 half points, which five units only tie.
+    assert_eq!(
+      fight(&[Some((0, 5)), Some((1, 1))], &[(0, 1)]),
+      [Some((0, 5)), Some((1, 1))]
+    );
+    assert_eq!(
+      fight(&[Some((0, 6)), Some((1, 1))], &[(0, 1)]),
+      [None, Some((0, 6))]
+    );
+  }
+
+  #[test]
+  fn income_preview_matches_the_turn() {
+    let mut state = line_board(2, &[Some((0, 3)), Some((0, 2)), None, None, Some((1, 1))]);
+    state.territories[0].sort = TerritorySort::Gold;
+    state.territories[1].sort = TerritorySort::Lab;
+    state.player_states[0].growth_level = 1;
+    order(&mut state, 0, Command::Grow);
+    let units = |state: &GameState| -> i32 {
+      state.territories.iter().filter_map(|terr| terr.contents).map(|(_, units)| units).sum()
+    };
+    let preview = state.income_preview(0);
+    assert_eq!(
+      preview,
+      IncomePreview {
+        gold:     1,
+        research: 1,
+        growth:   GROWTH_PER_TURN + 1,
+      }
+    );
+    let (gold, research, units_before) = (
+      state.player_states[0].gold,
+      state.player_states[0].research,
+      units(&state),
+    );
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.player_states[0].gold - gold, preview.gold);
+    assert_eq!(state.player_states[0].research - research, preview.research);
+    assert_eq!(units(&state) - units_before, preview.growth);
+  }
+
+  #[test]
+  fn capture_bonus_is_paid_when_a_territory_changes_hands() {
+    // Player 0 keeps a stack out of the way, so as not to be eliminated.
+    let mut state = line_board(
+      2,
+      &[Some((0, 6)), None, Some((1, 30)), None, None, Some((0, 1))],
+    );
+    state.config.capture_bonus_gold = 10;
+    state.config.economy_enabled = false;
+    // Nobody has held the middle yet.
+    order(&mut state, 0, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.player_states[0].gold, 10);
+    // Holding it pays nothing more.
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.player_states[0].gold, 10);
+    // Player 1 takes it, and player 0 takes it straight back.
+    order(&mut state, 2, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((1, 30)));
+    assert_eq!(state.player_states[1].gold, 10);
+    state.territories[0].contents = Some((0, 6));
+    state.territories[1].contents = Some((1, 1));
+    order(&mut state, 0, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 6)));
+    assert_eq!(state.player_states[0].gold, 20);
+  }
+
+  #[test]
+  fn moving_back_into_your_own_territory_pays_nothing() {
+    let mut state = line_board(2, &[Some((0, 3)), None, None, None, Some((1, 1))]);
+    state.config.capture_bonus_gold = 10;
+    state.config.economy_enabled = false;
+    order(&mut state, 0, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    order(&mut state, 1, Command::Attack { target: 0 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[0].contents, Some((0, 3)));
+    assert_eq!(state.player_states[0].gold, 10);
+  }
+
+  #[test]
+  fn capture_count_follows_changes_of_hands() {
+    let mut state = line_board(
+      2,
+      &[Some((0, 6)), None, Some((1, 30)), None, None, Some((0, 1))],
+    );
+    order(&mut state, 0, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    order(&mut state, 2, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((1, 30)));
+    assert_eq!(state.territories[1].capture_count, 2);
+    assert_eq!(state.most_contested_territory(), Some(1));
+    // Stepping out and back in isn't a change of hands.
+    order(&mut state, 1, Command::Attack { target: 2 });
+    resolve_turn(&mut state, &[]).unwrap();
+    order(&mut state, 2, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((1, 30)));
+    assert_eq!(state.territories[1].capture_count, 2);
+    assert_eq!(state.territories[5].capture_count, 0);
+  }
+
+  #[test]
+  fn predicted_losses_single_out_doomed_territories() {
+    let mut state = line_board(
+      2,
+      &[Some((0, 1)), Some((1, 20)), None, None, None, Some((0, 5))],
+    );
+    state.config.combat_mode = CombatMode::Random;
+    order(&mut state, 1, Command::Attack { target: 0 });
+    let rng_state = state.rng.state();
+    let losses = state.predicted_losses(0, 50);
+    assert!(losses[&0] > 0.9);
+    assert!(losses[&5] < 0.1);
+    assert_eq!(state.rng.state(), rng_state);
+  }
+
+  /// The wire `kind` of each `Command`.
+  fn command_kind(command: &Command) -> &'static str {
+    match command {
+      Command::Attack { .. } => "attack",
+      Command::Assault { .. } => "assault",
+      Command::Fortify => "fortify",
+      Command::Grow => "grow",
+      Command::Abandon { .. } => "abandon",
+    }
+  }
+
+  #[test]
+  fn commands_keep_their_wire_kinds() {
+    let commands = [
+      Command::Attack { target: 1 },
+      Command::Assault {
+        target: 1,
+        waves:  2,
+      },
+      Command::Fortify,
+      Command::Grow,
+      Command::Abandon {
+        retreat_to: Some(1),
+      },
+    ];
+    for command in commands {
+      let json = serde_json::to_value(command).unwrap();
+      assert_eq!(
+        json.get("kind").and_then(|kind| kind.as_str()),
+        Some(command_kind(&command))
+      );
+      assert_eq!(serde_json::from_value::<Command>(json).unwrap(), command);
+    }
+  }
+
+  #[test]
+  fn game_actions_keep_their_wire_kinds() {
+    let actions = [
+      (
+        "setCommand",
+        r#"{"kind":"setCommand","territory":0,"command":{"kind":"fortify"}}"#,
+      ),
+      ("resign", r#"{"kind":"resign"}"#),
+      (
+        "donate",
+        r#"{"kind":"donate","to":1,"gold":2,"research":3}"#,
+      ),
+    ];
+    for (kind, json) in actions {
+      let action: GameAction = serde_json::from_str(json).unwrap();
+      assert_eq!(action.kind(), kind);
+    }
+  }
+
+  #[test]
+  fn attack_levels_strengthen_attacks() {
+    // One unit fortified on land defends with 5 half points, which a single unit can't beat alone.
+    assert_eq!(
+      fight(&[Some((0, 1)), Some((1, 1))], &[(0, 1)]),
+      [Some((0, 1)), Some((1, 1))]
+    );
+    let mut state = line_board(2, &[Some((0, 1)), Some((1, 1))]);
+    state.player_states[0].attack_level = 3;
+    order(&mut state, 0, Command::Attack { target: 1 });
+    assert_eq!(state.explain_combat(1).incoming_half_attack_points, 7);
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 1)));
+  }
+
+  #[test]
+  fn combat_draws_from_the_rng_in_a_fixed_order() {
+    // Player 1 props up the middle from the right, while player 0 attacks it from the left.
+    let mut state = line_board(2, &[Some((0, 2)), Some((1, 1)), Some((1, 1))]);
+    state.config.combat_mode = CombatMode::Random;
+    order(&mut state, 0, Command::Attack { target: 1 });
+    order(&mut state, 2, Command::Attack { target: 1 });
+    state.rng.start_recording();
+    state.step_time().unwrap();
+    // If this changes, some refactor changed how many draws combat makes or in what order, which
+    // changes the outcome of every replayed game.
+    let golden: [u64; 14] = [
+      15864237570965628778,
+      18194340528229699284,
+      12851583597267946927,
+      16109630322971229772,
+      13622900745286275396,
+      3807888222129425762,
+      17890759056006870348,
+      11080480814438789302,
+      16390288628214787769,
+      16649508429303593826,
+      10109503013707986395,
+      9626428935377881636,
+      16499984648088439988,
+      13721613378318278668,
+    ];
+    assert_eq!(state.rng.take_tape(), golden);
+  }
+
+  #[test]
+  fn overlapping_territories_fail_only_when_checked() {
+    let mut state = line_board(2, &[Some((0, 1)), None, Some((1, 1))]);
+    assert!(state.validate_render_info(true, None).is_ok());
+    state.territories[2].render_info = state.territories[0].render_info;
+    assert!(state.validate_render_info(true, None).is_err());
+    assert!(state.validate_render_info(false, None).is_ok());
+  }
+
+  #[test]
+  fn territories_must_fall_within_the_render_bounds() {
+    let state = line_board(2, &[Some((0, 1)), None, Some((1, 1))]);
+    let bounds = |max_x| RenderBounds {
+      min: (0, 0),
+      max: (max_x, 0),
+    };
+    assert!(state.validate_render_info(false, Some(bounds(2))).is_ok());
+    assert!(state.validate_render_info(false, Some(bounds(1))).is_err());
+  }
+
+  #[test]
+  fn economic_victory_goes_to_the_richest_qualifying_player() {
+    let mut state = line_board(3, &[Some((0, 1)), Some((1, 1)), Some((2, 1))]);
+    state.config.victory_condition = VictoryCondition::Economic { gold: 50 };
+    state.player_states[0].gold = 49;
+    assert_eq!(state.outcome(), GameOutcome::Ongoing);
+    state.player_states[2].gold = 50;
+    assert_eq!(state.outcome(), GameOutcome::Victory { winner: 2 });
+    state.player_states[1].gold = 60;
+    assert_eq!(state.outcome(), GameOutcome::Victory { winner: 1 });
+    state.player_states[0].gold = 60;
+    assert_eq!(state.outcome(), GameOutcome::Victory { winner: 0 });
+  }
+
+  #[test]
+  fn domination_needs_the_share_held_for_enough_turns_in_a_row() {
+    let contents = [Some((0, 3)), Some((0, 3)), Some((0, 3)), None, Some((1, 3))];
+    let mut state = line_board(2, &contents);
+    state.config.victory_condition = VictoryCondition::Domination {
+      percent: 60,
+      turns:   2,
+    };
+    assert_eq!(state.step_time().unwrap().outcome, GameOutcome::Ongoing);
+    assert_eq!(state.victory_streaks, [1, 0]);
+    // Dropping below the share for a turn starts the count over.
+    state.territories[2].contents = None;
+    assert_eq!(state.step_time().unwrap().outcome, GameOutcome::Ongoing);
+    assert_eq!(state.victory_streaks, [0, 0]);
+    state.territories[2].contents = Some((0, 3));
+    assert_eq!(state.step_time().unwrap().outcome, GameOutcome::Ongoing);
+    assert_eq!(
+      state.step_time().unwrap().outcome,
+      GameOutcome::Victory { winner: 0 }
+    );
+  }
+
+  #[test]
+  fn king_of_the_hill_streak_passes_to_whoever_takes_the_hill() {
+    let mut state = line_board(2, &[Some((0, 3)), None, Some((1, 3))]);
+    state.config.victory_condition = VictoryCondition::KingOfTheHill {
+      territory: 2,
+      turns:     2,
+    };
+    assert_eq!(state.step_time().unwrap().outcome, GameOutcome::Ongoing);
+    assert_eq!(state.victory_streaks, [0, 1]);
+    state.territories[1].contents = Some((1, 3));
+    state.territories[2].contents = Some((0, 3));
+    assert_eq!(state.step_time().unwrap().outcome, GameOutcome::Ongoing);
+    assert_eq!(state.victory_streaks, [1, 0]);
+    assert_eq!(
+      state.step_time().unwrap().outcome,
+      GameOutcome::Victory { winner: 0 }
+    );
+  }
+
+  #[test]
+  fn last_standing_is_never_won_by_streaks() {
+    let mut state = line_board(2, &[Some((0, 3)), Some((0, 3)), None, Some((1, 3))]);
+    for _ in 0..3 {
+      assert_eq!(state.step_time().unwrap().outcome, GameOutcome::Ongoing);
+    }
+    assert_eq!(state.victory_streaks, [0, 0]);
+  }
+
+  #[test]
+  fn famine_stops_growth() {
+    let mut state = line_board(2, &[Some((0, 3)), None, Some((1, 3))]);
+    state.config.world_event_chance = 100;
+    let (mut famines, mut other_turns) = (0, 0);
+    for _ in 0..20 {
+      order(&mut state, 0, Command::Grow);
+      let before = state.territories[0].contents.unwrap().1;
+      state.step_time().unwrap();
+      let grown = state.territories[0].contents.unwrap().1 - before;
+      match state.world_event {
+        Some(WorldEvent::Famine) => {
+          assert_eq!(grown, 0);
+          famines += 1;
+        }
+        _ => {
+          assert_eq!(grown, GROWTH_PER_TURN);
+          other_turns += 1;
+        }
+      }
+    }
+    assert!(famines > 0 && other_turns > 0);
+  }
+
+  #[test]
+  fn orders_are_cleared_along_with_their_stack() {
+    let mut state = line_board(2, &[Some((0, 6)), Some((1, 1)), Some((1, 3))]);
+    state.config.orders_persist = true;
+    order(&mut state, 0, Command::Attack { target: 1 });
+    order(&mut state, 1, Command::Attack { target: 0 });
+    state.step_time().unwrap();
+    assert_eq!(state.territories[0].contents, None);
+    assert_eq!(
+      state.territories[1].contents.map(|(owner, _)| owner),
+      Some(0)
+    );
+    // Neither the dead stack's attack nor the movers' own order is left for the next turn.
+    assert_eq!(state.territories[0].command, Command::Fortify);
+    assert_eq!(state.territories[1].command, Command::Fortify);
+  }
+
+  #[test]
+  fn scenario_stacks_dont_inherit_stale_orders() {
+    let mut state = line_board(2, &[None, None, Some((1, 3))]);
+    state.territories[0].command = Command::Attack { target: 1 };
+    state.scenario = Some(Box::new(crate::scenario::MapScript::ScheduledSpawn {
+      turn:        0,
+      player:      0,
+      territories: vec![0],
+      units:       3,
+    }));
+    state.step_time().unwrap();
+    assert_eq!(state.territories[0].contents, Some((0, 3)));
+    assert_eq!(state.territories[1].contents, None);
+  }
+
+  #[test]
+  fn retreats_to_missing_territories_are_rejected() {
+    let mut state = line_board(2, &[Some((0, 3)), Some((0, 3)), Some((1, 3))]);
+    // A broken map file can list a neighbor that doesn't exist.
+    state.territories[0].adjacent.push(9);
+    let action = GameAction::SetCommand {
+      territory: 0,
+      command:   Command::Abandon {
+        retreat_to: Some(9),
+      },
+    };
+    assert!(state.process_action(&token(0), action).is_err());
+    let action = GameAction::SetCommand {
+      territory: 0,
+      command:   Command::Abandon {
+        retreat_to: Some(1),
+      },
+    };
+    assert!(state.process_action(&token(0), action).is_ok());
+  }
+
+  #[test]
+  fn donations_move_resources_between_allies_only() {
+    let mut state = line_board(3, &[Some((0, 3)), Some((1, 3)), Some((2, 3))]);
+    state.player_states[0].alliance_id = Some(7);
+    state.player_states[1].alliance_id = Some(7);
+    state.player_states[0].gold = 10;
+    state.player_states[0].research = 4;
+    let donate = |to, gold, research| GameAction::Donate { to, gold, research };
+    state.process_action(&token(0), donate(1, 6, 4)).unwrap();
+    assert_eq!(
+      (state.player_states[0].gold, state.player_states[0].research),
+      (4, 0)
+    );
+    assert_eq!(
+      (state.player_states[1].gold, state.player_states[1].research),
+      (6, 4)
+    );
+    // Not an ally, more than the donor has, negative, and to oneself.
+    assert!(state.process_action(&token(0), donate(2, 1, 0)).is_err());
+    assert!(state.process_action(&token(0), donate(1, 5, 0)).is_err());
+    assert!(state.process_action(&token(0), donate(1, -1, 0)).is_err());
+    assert!(state.process_action(&token(0), donate(0, 1, 0)).is_err());
+    // A donation that would overflow the recipient is turned away, and nothing changes hands.
+    state.player_states[1].gold = i32::MAX;
+    assert!(state.process_action(&token(0), donate(1, 1, 0)).is_err());
+    assert_eq!(state.player_states[0].gold, 4);
+    assert_eq!(state.player_states[1].gold, i32::MAX);
+  }
+
+  #[test]
+  fn income_preview_misses_gold_captured_this_turn() {
+    let mut state = line_board(2, &[Some((0, 8)), Some((1, 1)), Some((1, 3))]);
+    state.territories[1].sort = TerritorySort::Gold;
+    order(&mut state, 0, Command::Attack { target: 1 });
+    let previews = [state.income_preview(0), state.income_preview(1)];
+    assert_eq!((previews[0].gold, previews[1].gold), (0, 1));
+    state.step_time().unwrap();
+    assert_eq!(
+      state.territories[1].contents.map(|(owner, _)| owner),
+      Some(0)
+    );
+    // The mine pays whoever holds it once the fighting is done.
+    assert_eq!(
+      (state.player_states[0].gold, state.player_states[1].gold),
+      (1, 0)
+    );
+  }
+
+  #[test]
+  fn viewers_of_honors_range_and_forest_stealth() {
+    let mut contents = vec![None; 11];
+    contents[0] = Some((0, 3));
+    contents[2] = Some((1, 3));
+    contents[5] = Some((2, 3));
+    contents[10] = Some((0, 3));
+    let mut state = line_board(3, &contents);
+    state.territories[3].sort = TerritorySort::Forest;
+    // Two steps of sight would reach the forest, if forests could be seen from afar.
+    state.player_states[2].vision_level = 1;
+    assert_eq!(state.viewers_of(1), [0, 1]);
+    assert_eq!(state.viewers_of(3), [1]);
+    assert_eq!(state.viewers_of(4), [2]);
+    assert_eq!(state.viewers_of(10), [0]);
+  }
+
+  #[test]
+  fn eliminated_players_see_the_whole_board() {
+    let mut contents = vec![None; 8];
+    contents[0] = Some((0, 3));
+    contents[7] = Some((1, 3));
+    contents[4] = Some((1, 2));
+    let mut state = line_board(2, &contents);
+    let fogged = state.player_view(Some(0));
+    assert!(fogged.territories.iter().any(|terr| !terr.visible));
+    // Player 0 keeps territory 0, but is knocked out of the game all the same.
+    state.player_states[0].is_alive = false;
+    for view in [state.player_view(Some(0)), state.player_view(None)] {
+      assert!(view.territories.iter().all(|terr| terr.visible && terr.fog == FogLevel::Full));
+      let shown: Vec<_> = view.territories.iter().map(|terr| terr.contents).collect();
+      assert_eq!(shown, contents);
+    }
+  }
+
+  #[test]
+  fn distance_matrix_reports_steps_and_unreachable_territories() {
+    let mut state = line_board(1, &[None, None, None, None, None]);
+    // Cut the row in two between territories 2 and 3.
+    state.territories[2].adjacent.retain(|&adj| adj != 3);
+    state.territories[3].adjacent.retain(|&adj| adj != 2);
+    state.invalidate_distance_matrix();
+    let distances = state.distance_matrix();
+    assert_eq!(distances[0][0], 0);
+    assert_eq!(distances[0][2], 2);
+    assert_eq!(distances[2][0], 2);
+    assert_eq!(distances[3][4], 1);
+    assert_eq!(distances[0][3], u32::MAX);
+    assert_eq!(distances[4][1], u32::MAX);
+  }
+
+  #[test]
+  fn scorch_leaves_only_a_garrison_where_march_moves_everyone() {
+    let capture = |move_mode| {
+      let mut state = line_board(2, &[Some((0, 6)), None, Some((1, 3))]);
+      state.config.move_mode = move_mode;
+      order(&mut state, 0, Command::Attack { target: 1 });
+      state.step_time().unwrap();
+      assert_eq!(state.territories[0].contents, None);
+      state.territories[1].contents
+    };
+    assert_eq!(capture(MoveMode::March), Some((0, 6)));
+    assert_eq!(capture(MoveMode::Scorch), Some((0, SCORCH_GARRISON)));
+  }
+
+  #[test]
+  fn frontline_skips_interior_territories() {
+    let state = line_board(2, &[Some((0, 1)), Some((0, 1)), Some((1, 1)), None]);
+    assert_eq!(state.frontline(0), [1]);
+    assert_eq!(state.frontline(1), [2]);
+  }
+
+  #[test]
+  fn deterministic_move_ties_go_to_the_lowest_source() {
+    for seed in 0..20 {
+      let mut state = line_board(3, &[Some((2, 3)), Some((1, 3)), Some((0, 3)), None]);
+      state.rng = Rng::new_from_seed(seed);
+      // Every stack borders the empty territory at the end.
+      for terr in 0..3 {
+        state.territories[terr].adjacent = vec![3];
+        order(&mut state, terr, Command::Attack { target: 3 });
+      }
+      state.territories[3].adjacent = vec![0, 1, 2];
+      state.invalidate_distance_matrix();
+      resolve_turn(&mut state, &[]).unwrap();
+      assert_eq!(state.territories[3].contents, Some((2, 3)));
+    }
+  }
+
+  #[test]
+  fn merged_stacks_combine_but_still_fight_enemies() {
+    let merge = |state: &mut GameState| {
+      state.config.stack_policy = StackPolicy::Merge;
+      order(state, 0, Command::Attack { target: 1 });
+      order(state, 2, Command::Attack { target: 1 });
+    };
+    let mut state = line_board(2, &[Some((0, 3)), None, Some((0, 2))]);
+    merge(&mut state);
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 5)));
+    let mut state = line_board(2, &[Some((0, 3)), None, Some((0, 2))]);
+    state.config.max_units = Some(4);
+    merge(&mut state);
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 4)));
+    // An enemy stack arriving from the far side meets the merged stack in battle.
+    let mut state = line_board(2, &[Some((0, 3)), None, Some((0, 2)), Some((1, 4))]);
+    state.config.contested_moves = true;
+    state.territories[3].adjacent.push(1);
+    state.territories[1].adjacent.push(3);
+    state.invalidate_distance_matrix();
+    merge(&mut state);
+    order(&mut state, 3, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 1)));
+    assert_eq!(state.territories[3].contents, None);
+  }
+
+  #[test]
+  fn combat_explanations_add_up_by_hand() {
+    let mut state = line_board(2, &[Some((0, 10)), Some((1, 2)), Some((1, 1))]);
+    state.player_states[0].attack_level = 1;
+    state.player_states[1].defense_level = 1;
+    order(&mut state, 0, Command::Attack { target: 1 });
+    order(&mut state, 2, Command::Attack { target: 1 });
+    let explanation = state.explain_combat(1);
+    // Two fortified units on land: 2 per unit, 1 per unit for the land, 2 for fortifying, and 2 for
+    // the defense level.
+    let terms = DefenseTerms {
+      units:         4,
+      sort:          2,
+      fortify:       2,
+      defense_level: 2,
+    };
+    assert_eq!(explanation.defense_terms, Some(terms));
+    assert_eq!(explanation.supporters, [(2, 1)]);
+    assert_eq!(explanation.attackers, [(0, 10)]);
+    assert_eq!(explanation.half_defense_points, 10 + 1);
+    assert_eq!(explanation.incoming_half_attack_points, 10 + 2);
+    assert_eq!(explanation.attack_win_probability, 1.0);
+  }
+
+  #[test]
+  fn attacking_defense_ratio_sets_the_penalty_for_attacking() {
+    assert_eq!(GameConfig::default().attacking_half_defense_per_unit, 1);
+    let raid = |ratio| {
+      let mut state = line_board(2, &[Some((0, 7)), Some((1, 3)), Some((0, 1))]);
+      state.config.attacking_half_defense_per_unit = ratio;
+      order(&mut state, 0, Command::Attack { target: 1 });
+      order(&mut state, 1, Command::Attack { target: 2 });
+      resolve_turn(&mut state, &[]).unwrap();
+      state.territories[1].contents
+    };
+    // At half strength the three attacking units defend with 3 + 3 half points, which seven beat.
+    assert_eq!(raid(1), Some((0, 7)));
+    // At full strength they defend with 6 + 3, just like units that aren't fortifying.
+    assert_eq!(raid(2), Some((1, 3)));
+  }
+
+  #[test]
+  fn allies_share_vision_and_fight_side_by_side() {
+    let ally = |state: &mut GameState| {
+      state.player_states[0].alliance_id = Some(3);
+      state.player_states[1].alliance_id = Some(3);
+    };
+    let mut state = line_board(
+      2,
+      &[Some((0, 1)), None, None, None, None, None, Some((1, 1))],
+    );
+    assert!(!state.player_view(Some(0)).territories[6].visible);
+    ally(&mut state);
+    assert!(state.player_view(Some(0)).territories[6].visible);
+
+    // Attacking an ally only reinforces them.
+    let mut state = line_board(2, &[Some((0, 1)), Some((1, 9))]);
+    ally(&mut state);
+    order(&mut state, 1, Command::Attack { target: 0 });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[0].contents, Some((0, 1)));
+    assert_eq!(state.territories[1].contents, Some((1, 9)));
+
+    // One fortified unit on land defends with 5 half points, which seven attackers beat alone, but
+    // not with two allied units adding theirs.
+    let mut state = line_board(3, &[Some((1, 2)), Some((0, 1)), Some((2, 7))]);
+    order(&mut state, 2, Command::Attack { target: 1 });
+    let mut supported = state.clone();
+    ally(&mut supported);
+    order(&mut supported, 0, Command::Attack { target: 1 });
+    resolve_turn(&mut state, &[]).unwrap();
+    resolve_turn(&mut supported, &[]).unwrap();
+    assert_eq!(state.territories[1].contents, Some((2, 7)));
+    assert_eq!(supported.territories[1].contents, Some((0, 1)));
+  }
+
+  #[test]
+  fn abandoned_territories_empty_and_optionally_retreat() {
+    let mut state = line_board(2, &[Some((0, 5)), Some((0, 3)), Some((1, 1))]);
+    order(&mut state, 0, Command::Abandon { retreat_to: None });
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[0].contents, None);
+    assert_eq!(state.territories[1].contents, Some((0, 3)));
+    // Half of the five units, rounded down, fall back.
+    let mut state = line_board(2, &[Some((0, 5)), Some((0, 3)), Some((1, 1))]);
+    order(
+      &mut state,
+      0,
+      Command::Abandon {
+        retreat_to: Some(1),
+      },
+    );
+    resolve_turn(&mut state, &[]).unwrap();
+    assert_eq!(state.territories[0].contents, None);
+    assert_eq!(state.territories[1].contents, Some((0, 5)));
+  }
+
+  #[test]
+  fn state_hash_matches_identical_games_and_catches_any_change() {
+    let board = || line_board(2, &[Some((0, 3)), None, Some((1, 3))]);
+    let base = board().state_hash();
+    assert_eq!(board().state_hash(), base);
+    let changes: [fn(&mut GameState); 9] = [
+      |state| state.turn += 1,
+      |state| state.rng = Rng::new_from_seed(1),
+      |state| state.territories[1].sort = TerritorySort::Gold,
+      |state| state.territories[0].contents = Some((0, 4)),
+      |state| state.territories[0].command = Command::Grow,
+      |state| state.territories[2].veterancy = 1,
+      |state| state.player_states[1].gold = 1,
+      |state| state.player_states[0].attack_level = 1,
+      |state| state.player_states[0].alliance_id = Some(0),
+    ];
+    for change in changes {
+      let mut state = board();
+      change(&mut state);
+      assert_ne!(state.state_hash(), base);
+    }
+  }
+
+  #[test]
+  fn gold_income_decays_on_schedule_down_to_zero() {
+    let mut state = line_board(2, &[Some((0, 1)), None, None, Some((1, 1))]);
+    state.territories[0].sort = TerritorySort::Gold;
+    state.config.gold_decay = Some(GoldDecay {
+      initial_income: 3,
+      start_turn:     2,
+      turns_per_step: 2,
+    });
+    let mut incomes = vec![];
+    for _ in 0..9 {
+      let before = state.player_states[0].gold;
+      resolve_turn(&mut state, &[]).unwrap();
+      incomes.push(state.player_states[0].gold - before);
+    }
+    assert_eq!(incomes, [3, 3, 2, 2, 1, 1, 0, 0, 0]);
+  }
+
+  #[test]
+  fn eliminating_a_player_clears_their_territories_and_ends_the_game() {
+    let mut state = line_board(
+      3,
+      &[
+        Some((0, 3)),
+        Some((1, 3)),
+        Some((2, 3)),
+        Some((1, 3)),
+        Some((2, 1)),
+      ],
+    );
+    state.eliminate_player(1).unwrap();
+    assert!(!state.player_states[1].is_alive);
+    assert_eq!(state.territories[1].contents, None);
+    assert_eq!(state.territories[3].contents, None);
+    assert_eq!(state.outcome(), GameOutcome::Ongoing);
+    state.eliminate_player(2).unwrap();
+    assert!(state.territories.iter().all(|terr| !matches!(terr.contents, Some((2, _)))));
+    assert_eq!(state.outcome(), GameOutcome::Victory { winner: 0 });
+    assert!(state.eliminate_player(3).is_err());
+  }
+
+  #[test]
+  fn edge_of_vision_shows_the_owner_but_not_the_count() {
+    let state = line_board(2, &[Some((0, 3)), None, Some((1, 4)), Some((1, 1))]);
+    let view = state.player_view(Some(0));
+    let fog: Vec<FogLevel> = view.territories.iter().map(|terr| terr.fog).collect();
+    assert_eq!(
+      fog,
+      [
+        FogLevel::Full,
+        FogLevel::Full,
+        FogLevel::Partial,
+        FogLevel::Hidden
+      ]
+    );
+    assert_eq!(view.territories[2].contents, Some((1, 0)));
+    assert_eq!(view.territories[3].contents, None);
+  }
+
+  #[test]
+  fn attackers_of_leaves_out_allies() {
+    let mut state = line_board(4, &[Some((1, 2)), Some((0, 3)), Some((2, 2)), Some((3, 2))]);
+    state.player_states[0].alliance_id = Some(1);
+    state.player_states[3].alliance_id = Some(1);
+    state.territories[1].adjacent.push(3);
+    state.territories[3].adjacent = vec![1];
+    state.invalidate_distance_matrix();
+    for source in [0, 2, 3] {
+      order(&mut state, source, Command::Attack { target: 1 });
+    }
+    assert_eq!(state.attackers_of(1), [0, 2]);
+  }
+
+  #[test]
+  fn terrain_rules_retune_one_sort_at_a_time() {
+    let mut state = line_board(2, &[Some((0, 3)), Some((1, 3))]);
+    state.territories[0].sort = TerritorySort::Swamp;
+    let defense = |state: &GameState, terr| state.explain_combat(terr).half_defense_points;
+    // Three fortified units: 6 for the units and 2 for fortifying, then -2 on swamp or +3 on land.
+    assert_eq!((defense(&state, 0), defense(&state, 1)), (6, 11));
+    state.config.terrain.swamp = TerrainEffects::new(1, 1);
+    assert_eq!((defense(&state, 0), defense(&state, 1)), (12, 11));
+  }
+
+  #[test]
+  fn validate_rejects_self_adjacency_and_repeated_neighbors() {
+    let board = || line_board(2, &[Some((0, 3)), None, Some((1, 3))]);
+    assert!(board().validate().is_ok());
+    let mut state = board();
+    state.territories[1].adjacent.push(1);
+    let error = state.validate().unwrap_err().to_string();
+    assert_eq!(error, "Territory 1 is adjacent to itself");
+    let mut state = board();
+    state.territories[1].adjacent.push(0);
+    let error = state.validate().unwrap_err().to_string();
+    assert_eq!(
+      error,
+      "Territory 1 lists neighbor 0 more than once (again at position 2)"
+    );
+  }
+
+  #[test]
+  fn only_allies_see_each_others_orders() {
+    let mut state = line_board(3, &[Some((0, 3)), Some((1, 3)), Some((2, 3))]);
+    state.player_states[0].alliance_id = Some(2);
+    state.player_states[1].alliance_id = Some(2);
+    let attack = Command::Attack { target: 2 };
+    order(&mut state, 1, attack);
+    // Both players can see territory 1 in full, so only the alliance decides what they're shown.
+    assert_eq!(
+      state.player_view(Some(0)).territories[1].command,
+      Some(attack)
+    );
+    assert_eq!(state.player_view(Some(2)).territories[1].command, None);
+    assert!(state.player_view(Some(2)).territories[1].visible);
+  }
+
+  #[test]
+  fn two_islands_make_two_components() {
+    let mut state = line_board(2, &[Some((0, 1)), None, None, None, Some((1, 1))]);
+    // Cut the row between territories 1 and 2.
+    state.territories[1].adjacent.retain(|&adj| adj != 2);
+    state.territories[2].adjacent.retain(|&adj| adj != 1);
+    state.invalidate_distance_matrix();
+    assert_eq!(state.connected_components(), [vec![0, 1], vec![2, 3, 4]]);
+  }
+
+  #[test]
+  fn installed_rngs_decide_combat_exactly() {
+    let step = |seed| {
+      let mut state = line_board(2, &[Some((0, 8)), Some((1, 2)), Some((1, 4)), Some((0, 1))]);
+      state.config.combat_mode = CombatMode::Random;
+      order(&mut state, 0, Command::Attack { target: 1 });
+      order(&mut state, 2, Command::Attack { target: 3 });
+      state.set_rng(Rng::new_from_seed(seed));
+      let report = state.step_time().unwrap();
+      let contents: Vec<_> = state.territories.iter().map(|terr| terr.contents).collect();
+      (contents, report.animation_events)
+    };
+    let (contents, events) = step(0);
+    assert_eq!(contents, [Some((0, 8)), Some((1, 2)), None, Some((1, 4))]);
+    assert!(matches!(
+      events.as_slice(),
+      [
+        AnimationEvent::Death {
+          render_info: (3, 0),
+          attackers,
+          ..
+        },
+        AnimationEvent::Movement {
+          render_info_from: (2, 0),
+          render_info_to: (3, 0),
+          ..
+        },
+      ] if attackers == &[(2, 0)]
+    ));
+    let (contents, events) = step(2);
+    assert_eq!(
+      contents,
+      [Some((0, 8)), Some((1, 2)), Some((1, 4)), Some((0, 1))]
+    );
+    assert!(events.is_empty());
+  }
+
+  #[test]
+  fn deaths_list_every_attacker() {
+    // Neither stack of three beats one fortified unit alone, but together they do.
+    let mut state = line_board(2, &[Some((0, 3)), Some((1, 1)), Some((0, 3))]);
+    order(&mut state, 0, Command::Attack { target: 1 });
+    order(&mut state, 2, Command::Attack { target: 1 });
+    let report = state.step_time().unwrap();
+    let attackers = report.animation_events.iter().find_map(|event| match event {
+      AnimationEvent::Death {
+        render_info: (1, 0),
+        attackers,
+        ..
+      } => Some(attackers.clone()),
+      _ => None,
+    });
+    assert_eq!(attackers, Some(vec![(0, 0), (2, 0)]));
+  }
+
+  #[test]
+  fn resigned_players_get_a_distinct_error() {
+    let mut state = line_board(2, &[Some((0, 3)), Some((1, 3))]);
+    state.process_action(&token(0), GameAction::Resign).unwrap();
+    let action = GameAction::SetCommand {
+      territory: 0,
+      command:   Command::Grow,
+    };
+    let error = state.process_action(&token(0), action.clone()).unwrap_err();
+    assert!(error.downcast_ref::<PlayerEliminated>().is_some());
+    // The token still names the player, so their final state can be looked up.
+    assert_eq!(state.player_indices_by_token.get(&token(0)), Some(&0));
+    // A token left pointing past the player list is turned away rather than panicking.
+    state.player_indices_by_token.insert(token(5), 5);
+    assert!(state.process_action(&token(5), action).is_err());
+  }
+
+  #[test]
+  fn orders_either_persist_or_clear_after_each_turn() {
+    for orders_persist in [false, true] {
+      let mut state = line_board(2, &[Some((0, 3)), Some((1, 3))]);
+      state.config.orders_persist = orders_persist;
+      order(&mut state, 0, Command::Attack { target: 1 });
+      // Three units can't break three fortified ones, so the same attack is available every turn.
+      let attacks: Vec<u64> =
+        (0..2).map(|_| state.step_time().unwrap().combat_stats.attacks).collect();
+      match orders_persist {
+        true => {
+          assert_eq!(attacks, [1, 1]);
+          assert_eq!(state.territories[0].command, Command::Attack { target: 1 });
+        }
+        false => {
+          assert_eq!(attacks, [1, 0]);
+          assert_eq!(state.territories[0].command, Command::Fortify);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn attack_paths_run_through_friendly_territory() {
+    let mut state = line_board(
+      2,
+      &[
+        Some((0, 1)),
+        Some((0, 1)),
+        Some((0, 1)),
+        Some((1, 1)),
+        Some((1, 1)),
+      ],
+    );
+    assert_eq!(state.attack_path(0, 3, 0), Some(vec![0, 1, 2, 3]));
+    // The first enemy territory blocks the way to the one behind it.
+    assert_eq!(state.attack_path(0, 4, 0), None);
+    state.territories[1].adjacent.retain(|&adj| adj != 2);
+    state.territories[2].adjacent.retain(|&adj| adj != 1);
+    state.invalidate_distance_matrix();
+    assert_eq!(state.attack_path(0, 3, 0), None);
+  }
+
+  #[test]
+  fn combat_stats_count_attacks_captures_and_holds() {
+    let mut state = line_board(2, &[Some((0, 8)), Some((1, 1)), Some((1, 3)), Some((0, 3))]);
+    order(&mut state, 0, Command::Attack { target: 1 });
+    order(&mut state, 3, Command::Attack { target: 2 });
+    let stats = state.step_time().unwrap().combat_stats;
+    let expected = CombatStats {
+      attacks:    2,
+      captures:   1,
+      holds:      1,
+      units_lost: 1,
+    };
+    assert_eq!(stats, expected);
+  }
+
+  #[test]
+  fn resolve_turn_reports_a_whole_turn() {
+    let mut state = line_board(3, &[Some((0, 8)), Some((1, 1)), None, Some((2, 3))]);
+    let set_command = |territory, command| GameAction::SetCommand { territory, command };
+    let pending = [
+      (token(0), set_command(0, Command::Attack { target: 1 })),
+      (token(2), set_command(3, Command::Grow)),
+      // Territory 3 isn't player 1's to order.
+      (token(1), set_command(3, Command::Fortify)),
+    ];
+    let turn = resolve_turn(&mut state, &pending).unwrap();
+    assert_eq!(turn.rejected.len(), 1);
+    assert_eq!(turn.step.outcome, GameOutcome::Ongoing);
+    assert_eq!(state.territories[1].contents, Some((0, 8)));
+    assert_eq!(
+      state.territories[3].contents,
+      Some((2, 3 + GROWTH_PER_TURN))
+    );
+    assert_eq!(turn.views.len(), 3);
+    assert!(turn.views.iter().all(|view| view.turn == 1));
+    assert_eq!(turn.views[1].territories[1].contents, Some((0, 8)));
+    let saw_the_capture = |events: &[AnimationEvent]| {
+      events.iter().any(|event| {
+        matches!(
+          event,
+          AnimationEvent::Death {
+            render_info: (1, 0),
+            ..
+          }
+        )
+      })
+    };
+    assert!(saw_the_capture(&turn.events[0]) && saw_the_capture(&turn.events[1]));
+    assert!(!saw_the_capture(&turn.events[2]));
+  }
+
+  #[test]
+  fn contested_moves_fight_where_peaceful_ones_just_walk_in() {
+    let converge = |contested_moves| {
+      let mut state = line_board(2, &[Some((0, 5)), None, Some((1, 3))]);
+      state.config.contested_moves = contested_moves;
+      order(&mut state, 0, Command::Attack { target: 1 });
+      order(&mut state, 2, Command::Attack { target: 1 });
+      resolve_turn(&mut state, &[]).unwrap();
+      let contents: Vec<_> = state.territories.iter().map(|terr| terr.contents).collect();
+      contents
+    };
+    // The bigger stack walks in untouched, and the smaller one stays put.
+    assert_eq!(converge(false), [None, Some((0, 5)), Some((1, 3))]);
+    // The smaller stack is wiped out, and takes as many of the winners with it.
+    assert_eq!(converge(true), [None, Some((0, 2)), None]);
+  }
+
+  #[test]
+  fn soft_caps_make_big_stacks_win_less_often() {
+    let win_rate = |combat_soft_cap| {
+      let mut state = line_board(2, &[]);
+      state.config.combat_mode = CombatMode::Random;
+      state.config.combat_soft_cap = combat_soft_cap;
+      state.set_rng(Rng::new_from_seed(5));
+      (0..2000).filter(|_| state.roll_combat(20, 40, 0)).count()
+    };
+    assert_eq!(GameConfig::default().combat_soft_cap, None);
+    assert!(win_rate(Some(10)) < win_rate(None));
+  }
+
+  #[test]
+  fn captures_and_eliminations_are_logged_in_order() {
+    let mut state = line_board(2, &[Some((0, 8)), Some((1, 1))]);
+    order(&mut state, 0, Command::Attack { target: 1 });
+    state.step_time().unwrap();
+    let expected = [
+      GameEvent::TerritoryCaptured {
+        by:        0,
+        from:      Some(1),
+        territory: 1,
+      },
+      GameEvent::PlayerEliminated { player: 1 },
+    ];
+    assert_eq!(state.game_events, expected);
+  }
+
+  #[test]
+  fn captured_territories_drop_their_old_owners_orders() {
+    // Player 1's stack on territory 1 is ordered to back up its neighbor when it's overrun.
+    let mut state = line_board(2, &[Some((0, 8)), Some((1, 1)), Some((1, 1))]);
+    order(&mut state, 0, Command::Attack { target: 1 });
+    order(&mut state, 1, Command::Attack { target: 2 });
+    state.step_time().unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 8)));
+    assert_eq!(state.territories[1].command, Command::Fortify);
+    // Carried out by the new owner, that order would be an attack.
+    assert_eq!(state.step_time().unwrap().combat_stats.attacks, 0);
+    assert_eq!(state.territories[2].contents, Some((1, 1)));
+  }
+
+  #[test]
+  fn starting_towers_see_further_from_the_first_view() {
+    let mut state = line_board(2, &[Some((0, 1)), None, None, None, None, Some((1, 1))]);
+    assert!(!state.player_view(Some(0)).territories[2].visible);
+    state.territories[0].sort = TerritorySort::Tower;
+    let view = state.player_view(Some(0));
+    assert!(view.territories[2].visible);
+    assert_eq!(view.territories[3].fog, FogLevel::Partial);
+  }
+
+  #[test]
+  fn scheduled_spawns_fire_on_their_turn_only() {
+    let mut state = line_board(2, &[Some((0, 1)), None, None, Some((1, 1))]);
+    state.scenario = Some(Box::new(crate::scenario::MapScript::ScheduledSpawn {
+      turn:        2,
+      player:      0,
+      territories: vec![1, 3],
+      units:       5,
+    }));
+    let mut spawned = vec![];
+    for _ in 0..5 {
+      state.step_time().unwrap();
+      spawned.push(state.territories[1].contents);
+      // Territory 3 is occupied, so it's skipped.
+      assert_eq!(state.territories[3].contents, Some((1, 1)));
+    }
+    assert_eq!(
+      spawned,
+      [None, None, Some((0, 5)), Some((0, 5)), Some((0, 5))]
+    );
+  }
+
+  #[test]
+  fn largest_contiguous_counts_the_biggest_cluster() {
+    let mut contents = vec![Some((0, 1)); 9];
+    contents[3] = Some((1, 1));
+    let state = line_board(2, &contents);
+    assert_eq!(state.largest_contiguous(0), 5);
+    assert_eq!(state.largest_contiguous(1), 1);
+  }
+
+  #[test]
+  fn veterans_hold_more_often_and_lose_their_veterancy_when_captured() {
+    let holds = |veterancy| {
+      (0..500)
+        .filter(|&seed| {
+          let mut state = line_board(2, &[Some((0, 9)), Some((1, 2))]);
+          state.config.combat_mode = CombatMode::Random;
+          state.config.veterancy_bonus = 2;
+          state.territories[1].veterancy = veterancy;
+          state.set_rng(Rng::new_from_seed(seed));
+          order(&mut state, 0, Command::Attack { target: 1 });
+          state.step_time().unwrap();
+          state.territories[1].contents == Some((1, 2))
+        })
+        .count()
+    };
+    assert!(holds(3) > holds(0));
+
+    let mut state = line_board(2, &[Some((0, 3)), Some((1, 1))]);
+    order(&mut state, 0, Command::Attack { target: 1 });
+    state.step_time().unwrap();
+    // Holding out earns a point of veterancy, which goes when the territory falls.
+    assert_eq!(state.territories[1].veterancy, 1);
+    state.territories[0].contents = Some((0, 8));
+    state.step_time().unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 8)));
+    assert_eq!(state.territories[1].veterancy, 0);
+  }
+
+  #[test]
+  fn validation_agrees_with_applying() {
+    let set_command = |territory, command| GameAction::SetCommand { territory, command };
+    let cases = [
+      (0, set_command(0, Command::Attack { target: 1 })),
+      (0, set_command(0, Command::Attack { target: 2 })),
+      (0, set_command(1, Command::Grow)),
+      (0, set_command(9, Command::Grow)),
+      (1, set_command(1, Command::Abandon { retreat_to: None })),
+      (5, set_command(1, Command::Grow)),
+      (
+        0,
+        GameAction::Donate {
+          to:       1,
+          gold:     1,
+          research: 0,
+        },
+      ),
+      (1, GameAction::Resign),
+    ];
+    for (player, action) in cases {
+      let mut state = line_board(2, &[Some((0, 3)), Some((1, 3)), None]);
+      state.player_states[0].gold = 5;
+      let validated = state.validate_action(&token(player), &action).is_ok();
+      let before = state.state_hash();
+      let applied = state.process_action(&token(player), action.clone()).is_ok();
+      assert_eq!(validated, applied, "{:?}", action);
+      if !applied {
+        assert_eq!(state.state_hash(), before, "{:?}", action);
+      }
+    }
+  }
+
+  #[test]
+  fn zero_unit_stacks_are_cleared_and_can_be_moved_into() {
+    let mut state = line_board(2, &[Some((0, 3)), Some((1, 0)), Some((1, 3))]);
+    let action = GameAction::SetCommand {
+      territory: 1,
+      command:   Command::Attack { target: 0 },
+    };
+    assert!(state.process_action(&token(1), action).is_err());
+    state.step_time().unwrap();
+    assert_eq!(state.territories[1].contents, None);
+    order(&mut state, 0, Command::Attack { target: 1 });
+    state.step_time().unwrap();
+    assert_eq!(state.territories[1].contents, Some((0, 3)));
+  }
+
+  #[test]
+  fn seeded_storms_blunt_that_turns_attacks() {
+    let raid = |seed| {
+      let mut state = line_board(2, &[Some((0, 40)), Some((1, 1)), Some((1, 1))]);
+      state.set_rng(Rng::new_from_seed(seed));
+      state.config.combat_mode = CombatMode::Random;
+      // On two-sided dice a storm turns every attack roll into a zero, so no attack can win.
+      state.config.dice_sides = 2;
+      state.config.world_event_chance = 100;
+      order(&mut state, 0, Command::Attack { target: 1 });
+      state.step_time().unwrap();
+      state
+    };
+    let storm = raid(0);
+    assert_eq!(storm.world_event, Some(WorldEvent::Storm));
+    assert_eq!(
+      storm.game_events,
+      [GameEvent::WorldEvent {
+        event: WorldEvent::Storm,
+        turn:  0,
+      }]
+    );
+    assert_eq!(storm.territories[1].contents, Some((1, 1)));
+    // Forty units break a lone defender easily in fair weather.
+    let famine = raid(1);
+    assert_eq!(famine.world_event, Some(WorldEvent::Famine));
+    assert_eq!(famine.territories[1].contents, Some((0, 40)));
+  }
+
+  #[test]
+  fn public_json_hides_tokens_and_full_json_round_trips() {
+    let mut state = line_board(2, &[Some((0, 3)), None, Some((1, 2))]);
+    state.player_indices_by_token.clear();
+    let tokens = [generate_player_token(), generate_player_token()];
+    for (player, token) in tokens.iter().enumerate() {
+      state.player_indices_by_token.insert(token.clone(), player);
+    }
+    order(&mut state, 0, Command::Attack { target: 1 });
+    for viewer in [None, Some(0), Some(1)] {
+      let public = state.to_public_json(viewer).unwrap();
+      for token in &tokens {
+        assert!(!public.contains(token.as_str()), "{viewer:?} saw {token}");
+      }
+    }
+    let full = state.to_full_json().unwrap();
+    for token in &tokens {
+      assert!(full.contains(token.as_str()));
+    }
+    let restored = GameState::from_full_json(&full).unwrap();
+    assert_eq!(restored.to_full_json().unwrap(), full);
+    assert_eq!(
+      restored.player_indices_by_token,
+      state.player_indices_by_token
+    );
+  }
+
+  #[test]
+  fn catch_up_reinforces_only_trailing_players() {
+    let catch_up = Some(CatchUp {
+      threshold_percent:  50,
+      max_units_per_turn: 2,
+    });
+    // Holding one territory to the leader's five is a deficit of four, capped at two units.
+    let mut lopsided = line_board(2, &[Some((0, 1)); 5]);
+    lopsided.territories.push(Territory {
+      contents: Some((1, 1)),
+      ..lopsided.territories[4].clone()
+    });
+    lopsided.config.catch_up = catch_up.clone();
+    assert_eq!(lopsided.grant_catch_up(), HashMap::from([(5, 2)]));
+    assert_eq!(lopsided.territories[5].contents, Some((1, 3)));
+    assert!(lopsided.territories[..5].iter().all(|terr| terr.contents == Some((0, 1))));
+
+    let mut close = line_board(
+      2,
+      &[
+        Some((0, 1)),
+        Some((0, 1)),
+        Some((0, 1)),
+        Some((1, 1)),
+        Some((1, 1)),
+      ],
+    );
+    close.config.catch_up = catch_up;
+    assert!(close.grant_catch_up().is_empty());
+    assert_eq!(close.territories[3].contents, Some((1, 1)));
+    assert_eq!(close.territories[4].contents, Some((1, 1)));
+  }
+
+  #[test]
+  fn assaults_keep_attacking_until_they_win_or_run_out() {
+    // Four units never break one fortified unit, so this assault just runs out of waves.
+    let mut state = line_board(2, &[Some((0, 4)), Some((1, 1))]);
+    order(
+      &mut state,
+      0,
+      Command::Assault {
+        target: 1,
+        waves:  2,
+      },
+    );
+    for waves_left in [Some(1), None] {
+      assert_eq!(state.step_time().unwrap().combat_stats.attacks, 1);
+      let command = waves_left.map_or(Command::Fortify, |waves| Command::Assault {
+        target: 1,
+        waves,
+      });
+      assert_eq!(state.territories[0].command, command);
+    }
+    assert_eq!(state.step_time().unwrap().combat_stats.attacks, 0);
+    assert_eq!(state.territories[1].contents, Some((1, 1)));
+
+    // Six units bounce off two fortified units, but break through once the defense is down to one.
+    let mut state = line_board(2, &[Some((0, 6)), Some((1, 2))]);
+    order(
+      &mut state,
+      0,
+      Command::Assault {
+        target: 1,
+        waves:  3,
+      },
+    );
+    state.step_time().unwrap();
+    assert_eq!(state.territories[1].contents, Some((1, 2)));
+    assert_eq!(
+      state.territories[0].command,
+      Command::Assault {
+        target: 1,
+        waves:  2,
+      }
+    );
+    state.territories[1].contents = Some((1, 1));
+    state.step_time().unwrap();
+    assert_eq!(
+      state.territories[1].contents.map(|(owner, _)| owner),
+      Some(0)
+    );
+    assert_eq!(state.territories[0].command, Command::Fortify);
+    assert_eq!(state.step_time().unwrap().combat_stats.attacks, 0);
+  }
+
+  #[test]
+  fn attacks_reveal_attackers_to_the_defender_under_the_rule() {
+    for attacks_reveal_attackers in [false, true] {
+      // Player 1's last territory is too far off to see the stack that takes their front line.
+      let mut contents = vec![Some((0, 10)), Some((1, 1))];
+      contents.resize(BASE_VISION_RANGE as usize + 4, None);
+      contents.push(Some((1, 1)));
+      let mut state = line_board(2, &contents);
+      state.config.attacks_reveal_attackers = attacks_reveal_attackers;
+      order(&mut state, 0, Command::Attack { target: 1 });
+      state.step_time().unwrap();
+      assert_eq!(
+        state.territories[1].contents.map(|(owner, _)| owner),
+        Some(0)
+      );
+      let view = state.player_view(Some(1));
+      assert_eq!(view.territories[0].visible, attacks_reveal_attackers);
+    }
+  }
+
+  #[test]
+  fn territories_are_found_by_render_position() {
+    let mut state = line_board(2, &[Some((0, 1)), None, Some((1, 1))]);
+    for (i, terr) in state.territories.iter().enumerate() {
+      assert_eq!(state.territory_at_render(terr.render_info), Some(i));
+    }
+    assert_eq!(state.territory_at_render((0, 1)), None);
+    // Moving a territory only shows up once the cached lookup is invalidated.
+    state.territories[2].render_info = (0, 1);
+    state.invalidate_render_index();
+    assert_eq!(state.territory_at_render((0, 1)), Some(2));
+    assert_eq!(state.territory_at_render((2, 0)), None);
+  }
+
+  #[test]
+  fn best_attacks_send_the_big_stack_at_the_weak_neighbor() {
+    // Territory 1 falls to territory 0's stack alone, and nothing player 0 has can touch territory
+    // 3, so neither the straggler on 2 nor the hopeless attack gets recommended.
+    let state = line_board(
+      2,
+      &[Some((0, 20)), Some((1, 1)), Some((0, 1)), Some((1, 20))],
+    );
+    assert_eq!(state.best_attack_targets(0), [(0, 1)]);
   }
 }
@@ -1,2 +1,7 @@
+pub mod ai;
 pub mod game_state;
+pub mod map_file;
+pub mod map_gen;
 pub mod rng;
+pub mod scenario;
+pub mod state_diff;
@@ -0,0 +1,209 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, Context, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  game_state::{
+    Command, GameState, PlayerIndex, PlayerStartLevels, PlayerState, RenderBounds, Territory,
+    TerritoryIndex, TerritorySort,
+  },
+  scenario::MapScript,
+};
+
+/// A hand-made map, as stored in a `.json` file in the maps directory.
+#[derive(Debug, Clone, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct MapFile {
+  pub player_count:    usize,
+  pub territories:     Vec<MapTerritory>,
+  /// Per-player starting upgrade levels. If empty, everyone starts at zero; otherwise there must be
+  /// exactly one entry per player.
+  #[serde(default)]
+  pub starting_levels: Vec<PlayerStartLevels>,
+  /// Scenario logic to run each turn, for campaign maps.
+  #[serde(default)]
+  pub script:          Option<MapScript>,
+  /// If set, every territory has to be drawn within these bounds.
+  #[serde(default)]
+  pub render_bounds:   Option<RenderBounds>,
+  /// If set, no two territories may be drawn at the same `render_info`.
+  #[serde(default)]
+  pub check_overlaps:  bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct MapTerritory {
+  pub sort:        TerritorySort,
+  pub adjacent:    Vec<TerritoryIndex>,
+  pub render_info: (i32, i32),
+  /// The player (and how many of their units) starting here, if any.
+  pub contents:    Option<(PlayerIndex, i32)>,
+}
+
+impl MapFile {
+  /// Builds a fresh game on this map, failing if the map doesn't pass `GameState::validate` and
+  /// `GameState::validate_render_info`.
+  pub fn to_game_state(&self, seed: u64) -> Result<GameState, Error> {
+    let mut state = GameState::new(seed);
+    state.territories = self
+      .territories
+      .iter()
+      .map(|terr| Territory {
+        sort:           terr.sort,
+        contents:       terr.contents,
+        command:        Command::Fortify,
+        adjacent:       terr.adjacent.clone(),
+        render_info:    terr.render_info,
+        veterancy:      0,
+        capture_count:  0,
+        previous_owner: None,
+        last_owner:     terr.contents.map(|(owner, _)| owner),
+      })
+      .collect();
+    state.player_states = (0..self.player_count).map(PlayerState::new).collect();
+    if !self.starting_levels.is_empty() {
+      state
+        .apply_starting_levels(&self.starting_levels)
+        .context("Map has bad starting levels")?;
+    }
+    if let Some(script) = &self.script {
+      state.scenario = Some(Box::new(script.clone()));
+    }
+    state.validate().context("Map failed validation")?;
+    state
+      .validate_render_info(self.check_overlaps, self.render_bounds)
+      .context("Map has bad render info")?;
+    Ok(state)
+  }
+}
+
+/// Parses a map without validating it. serde_json's errors already carry the line and column.
+fn parse_map_json(json: &str) -> Result<MapFile, Error> {
+  serde_json::from_str(json).context("Map isn't valid map JSON")
+}
+
+impl GameState {
+  /// Parses and validates a map in the `MapFile` JSON format. Errors name the offending line or
+  /// territory, so map authors can find the problem.
+  pub fn from_map_json(json: &str, seed: u64) -> Result<GameState, Error> {
+    parse_map_json(json)?.to_game_state(seed)
+  }
+}
+
+/// Every valid map found in the maps directory, by file name without the `.json` extension.
+#[derive(Debug, Default)]
+pub struct MapRegistry {
+  maps: HashMap<String, MapFile>,
+}
+
+impl MapRegistry {
+  /// Loads every `.json` map in `dir`. Maps that fail to parse or validate are logged and skipped,
+  /// so one bad file can't keep the server from starting.
+  pub fn load_directory(dir: &Path) -> Result<Self, Error> {
+    let mut registry = Self::default();
+    for entry in std::fs::read_dir(dir)? {
+      let path = entry?.path();
+      if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        continue;
+      }
+      let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(name) => name.to_string(),
+        None => continue,
+      };
+      match Self::load_map(&path) {
+        Ok(map_file) => {
+          registry.maps.insert(name, map_file);
+        }
+        // Print the whole context chain, since the innermost error is the one that says what's wrong.
+        Err(err) => println!("Skipping invalid map {}: {:#}", path.display(), err),
+      }
+    }
+    Ok(registry)
+  }
+
+  fn load_map(path: &Path) -> Result<MapFile, Error> {
+    let map_file = parse_map_json(&std::fs::read_to_string(path)?)?;
+    // Build a throwaway game just to run the full validation.
+    map_file.to_game_state(0)?;
+    Ok(map_file)
+  }
+
+  pub fn get(&self, name: &str) -> Result<&MapFile, Error> {
+    self.maps.get(name).ok_or_else(|| anyhow!("Map not found: {}", name))
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.maps.is_empty()
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.maps.keys().map(|name| name.as_str())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn two_player_map(adjacent_to_first: TerritoryIndex) -> MapFile {
+    let territory = |adjacent, x, owner| MapTerritory {
+      sort:        TerritorySort::Land,
+      adjacent:    vec![adjacent],
+      render_info: (x, 0),
+      contents:    Some((owner, 3)),
+    };
+    MapFile {
+      player_count:    2,
+      territories:     vec![territory(adjacent_to_first, 0, 0), territory(0, 1, 1)],
+      starting_levels: vec![],
+      script:          None,
+      render_bounds:   None,
+      check_overlaps:  false,
+    }
+  }
+
+  #[test]
+  fn registry_skips_invalid_maps() {
+    let dir = std::env::temp_dir().join(format!("mapwar-maps-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, map: &MapFile| {
+      std::fs::write(dir.join(name), serde_json::to_string(map).unwrap()).unwrap();
+    };
+    write("duel.json", &two_player_map(1));
+    // Adjacent to a territory that doesn't exist.
+    write("broken.json", &two_player_map(5));
+    std::fs::write(dir.join("garbled.json"), "{ not json").unwrap();
+    let registry = MapRegistry::load_directory(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let registry = registry.unwrap();
+    assert_eq!(registry.names().collect::<Vec<_>>(), ["duel"]);
+    assert!(registry.get("broken").is_err());
+  }
+
+  #[test]
+  fn map_errors_name_the_territory_and_problem() {
+    let error_for = |map: MapFile| {
+      let json = serde_json::to_string(&map).unwrap();
+      format!("{:#}", GameState::from_map_json(&json, 0).unwrap_err())
+    };
+    let error = error_for(two_player_map(5));
+    assert!(error.contains("Territory 0 lists adjacency 5 which is out of range [0, 2)"));
+    let mut map = two_player_map(1);
+    map.territories[1].contents = Some((1, 0));
+    assert!(error_for(map).contains("Territory 1 starts with 0 units"));
+    let mut map = two_player_map(1);
+    map.territories[1].adjacent.clear();
+    assert!(
+      error_for(map).contains("Territory 0 is adjacent to territory 1, but not the other way")
+    );
+    let mut map = two_player_map(1);
+    map.territories[1].contents = Some((4, 3));
+    assert!(error_for(map).contains("Territory 1 is owned by player 4"));
+    let error = GameState::from_map_json("{\n  \"playerCount\": 2,\n  oops", 0).unwrap_err();
+    assert!(format!("{:#}", error).contains("line 3"));
+  }
+}
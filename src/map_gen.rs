@@ -0,0 +1,185 @@
+use std::{
+  collections::HashMap,
+  sync::{Mutex, PoisonError},
+};
+
+use crate::{
+  game_state::{Command, GameState, PlayerState, StartMetrics, Territory, TerritorySort},
+  rng::Rng,
+};
+
+/// How many units each player starts with on their home territory.
+pub const STARTING_UNITS: i32 = 4;
+/// Layouts where two players' nearby Gold (or Lab) counts differ by more than this get rerolled.
+pub const MAX_RESOURCE_SPREAD: usize = 1;
+/// How many layouts to try before settling for the most balanced one seen.
+const MAX_LAYOUT_ATTEMPTS: u64 = 32;
+/// How many maps a `MapCache` holds before it starts forgetting them.
+pub const MAX_CACHED_MAPS: usize = 64;
+
+fn random_sort(rng: &mut Rng) -> TerritorySort {
+  match rng.generate() % 20 {
+    0 | 1 => TerritorySort::Swamp,
+    2 | 3 => TerritorySort::Forest,
+    4 => TerritorySort::Tower,
+    5 => TerritorySort::Gold,
+    6 => TerritorySort::Lab,
+    _ => TerritorySort::Land,
+  }
+}
+
+/// Returns how far apart the best- and worst-off players are in nearby Gold or Labs. Layouts that
+/// leave players in different-sized regions are never balanced, so they get the worst score.
+fn resource_spread(state: &GameState) -> usize {
+  let report = state.starting_balance_report();
+  if report.iter().any(|metrics| metrics.region_size != report[0].region_size) {
+    return usize::MAX;
+  }
+  let spread = |count: fn(&StartMetrics) -> usize| {
+    let max = report.iter().map(count).max().unwrap_or(0);
+    let min = report.iter().map(count).min().unwrap_or(0);
+    max - min
+  };
+  spread(|metrics| metrics.nearby_gold).max(spread(|metrics| metrics.nearby_labs))
+}
+
+/// Generates a square board of hexes (in "odd-r" offset coordinates) with one home territory per player.
+///
+/// Layouts that hand some player noticeably more resources nearby are rerolled a bounded number of
+/// times. Everything is drawn from RNGs seeded from `seed`, so the same seed always gives the same map.
+pub fn generate_hex_map(seed: u64, player_count: usize) -> GameState {
+  let mut best_layout = generate_layout(seed, player_count);
+  let mut best_spread = resource_spread(&best_layout);
+  for attempt in 1..MAX_LAYOUT_ATTEMPTS {
+    if best_spread <= MAX_RESOURCE_SPREAD {
+      break;
+    }
+    let layout = generate_layout(seed.wrapping_add(attempt), player_count);
+    let spread = resource_spread(&layout);
+    if spread < best_spread {
+      best_layout = layout;
+      best_spread = spread;
+    }
+  }
+  best_layout
+}
+
+/// Remembers maps from `generate_hex_map`, so generating the same one again is just a clone.
+///
+/// Generation is deterministic in `(seed, player_count)`, so a cached map is exactly what a fresh
+/// generation would give, down to the RNG state play picks up from.
+#[derive(Debug, Default)]
+pub struct MapCache {
+  maps: Mutex<HashMap<(u64, usize), GameState>>,
+}
+
+impl MapCache {
+  pub fn generate_hex_map(&self, seed: u64, player_count: usize) -> GameState {
+    let key = (seed, player_count);
+    if let Some(state) = self.maps.lock().unwrap_or_else(PoisonError::into_inner).get(&key) {
+      return state.clone();
+    }
+    // Generate without holding the lock, so other games aren't held up behind us.
+    let state = generate_hex_map(seed, player_count);
+    let mut maps = self.maps.lock().unwrap_or_else(PoisonError::into_inner);
+    if maps.len() >= MAX_CACHED_MAPS {
+      // Most seeds are random, so there's little point being clever about what to forget.
+      maps.clear();
+    }
+    maps.insert(key, state.clone());
+    state
+  }
+}
+
+fn generate_layout(seed: u64, player_count: usize) -> GameState {
+  let mut state = GameState::new(seed);
+  let side = 4 + 2 * player_count;
+  let index_of = |col: usize, row: usize| row * side + col;
+
+  for row in 0..side {
+    for col in 0..side {
+      let mut adjacent = vec![];
+      // In odd-r layout the odd rows are shifted half a hex to the right.
+      let diagonal_cols = match row % 2 {
+        0 => [col.checked_sub(1), Some(col)],
+        _ => [Some(col), Some(col + 1)],
+      };
+      if col > 0 {
+        adjacent.push(index_of(col - 1, row));
+      }
+      if col + 1 < side {
+        adjacent.push(index_of(col + 1, row));
+      }
+      for neighbor_row in [row.checked_sub(1), Some(row + 1)].into_iter().flatten() {
+        if neighbor_row >= side {
+          continue;
+        }
+        for neighbor_col in diagonal_cols.into_iter().flatten() {
+          if neighbor_col < side {
+            adjacent.push(index_of(neighbor_col, neighbor_row));
+          }
+        }
+      }
+      let sort = random_sort(&mut state.rng);
+      state.territories.push(Territory {
+        sort,
+        contents: None,
+        command: Command::Fortify,
+        adjacent,
+        render_info: (col as i32, row as i32),
+        veterancy: 0,
+        capture_count: 0,
+        previous_owner: None,
+        last_owner: None,
+      });
+    }
+  }
+
+  // Drop each player onto a distinct random territory, which we flatten to plain land.
+  let homes = state.rng.sample_distinct(state.territories.len(), player_count);
+  for (player_index, home) in homes.into_iter().enumerate() {
+    state.territories[home].sort = TerritorySort::Land;
+    state.territories[home].contents = Some((player_index, STARTING_UNITS));
+    state.player_states.push(PlayerState::new(player_index));
+  }
+
+  state
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn players_start_with_about_the_same_gold_nearby() {
+    for seed in 0..10 {
+      for player_count in 2..=4 {
+        let report = generate_hex_map(seed, player_count).starting_balance_report();
+        let gold = report.iter().map(|metrics| metrics.nearby_gold);
+        let spread = gold.clone().max().unwrap() - gold.min().unwrap();
+        assert!(
+          spread <= MAX_RESOURCE_SPREAD,
+          "seed {} with {} players",
+          seed,
+          player_count
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn cached_maps_match_fresh_ones() {
+    let cache = MapCache::default();
+    let first = cache.generate_hex_map(7, 2);
+    let second = cache.generate_hex_map(7, 2);
+    assert_eq!(cache.maps.lock().unwrap().len(), 1);
+    let fresh = generate_hex_map(7, 2);
+    for state in [&first, &second] {
+      assert_eq!(state.territories, fresh.territories);
+      assert_eq!(state.state_hash(), fresh.state_hash());
+    }
+    assert_ne!(cache.generate_hex_map(8, 2).territories, first.territories);
+    assert_ne!(cache.generate_hex_map(7, 3).territories, first.territories);
+    assert_eq!(cache.maps.lock().unwrap().len(), 3);
+  }
+}
@@ -1,23 +1,85 @@
-#[derive(Debug, Clone)]
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// Where a new game's seed comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum SeedSource {
+  /// Drawn from the OS's CSPRNG, so nobody can predict or replay the game.
+  Random,
+  /// A known seed, so a custom game can be replayed exactly.
+  Fixed { seed: u64 },
+}
+
+impl SeedSource {
+  pub fn resolve(self) -> u64 {
+    match self {
+      SeedSource::Random => rand::rngs::OsRng.next_u64(),
+      SeedSource::Fixed { seed } => seed,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Rng {
   state: u64,
+  /// While recording, every value `generate` hands out, in order.
+  #[serde(skip)]
+  tape:  Option<Vec<u64>>,
 }
 
 impl Rng {
   pub fn new() -> Self {
+    Self::new_from_seed(rand::random())
+  }
+
+  pub fn new_from_seed(seed: u64) -> Self {
     Self {
-      state: rand::random(),
+      state: seed,
+      tape:  None,
     }
   }
 
-  pub fn new_from_seed(seed: u64) -> Self {
-    Self { state: seed }
+  /// The raw internal state, which is enough to predict every future roll.
+  pub fn state(&self) -> u64 {
+    self.state
+  }
+
+  /// Derives an independent generator for `stream`, leaving this one untouched.
+  pub fn fork(&self, stream: u64) -> Self {
+    let mut mixer = Self::new_from_seed(self.state ^ stream.rotate_left(32));
+    Self::new_from_seed(mixer.generate())
   }
 
   pub fn generate(&mut self) -> u64 {
     self.state += 1;
+    let value = Self::mix(self.state);
+    if let Some(tape) = &mut self.tape {
+      tape.push(value);
+    }
+    value
+  }
+
+  /// Starts recording every value `generate` returns, discarding anything recorded so far. Comparing
+  /// tapes catches any change to how many draws a step makes, or in what order.
+  pub fn start_recording(&mut self) {
+    self.tape = Some(vec![]);
+  }
+
+  /// Stops recording and returns the values drawn since `start_recording`.
+  pub fn take_tape(&mut self) -> Vec<u64> {
+    self.tape.take().unwrap_or_default()
+  }
+
+  /// Returns what the next `generate` will, without advancing the stream.
+  pub fn peek(&self) -> u64 {
+    Self::mix(self.state + 1)
+  }
+
+  fn mix(state: u64) -> u64 {
     const MULT: u64 = 0x243f6a8885a308d3;
-    let mut x = self.state;
+    let mut x = state;
     for _ in 0..3 {
       x = x.wrapping_mul(MULT);
       x ^= x >> 37;
@@ -25,4 +87,64 @@ impl Rng {
     x = x.wrapping_mul(MULT);
     x
   }
+
+  /// Returns `k` distinct indices from `[0, n)`, in random order, via a partial Fisher-Yates shuffle.
+  pub fn sample_distinct(&mut self, n: usize, k: usize) -> Vec<usize> {
+    assert!(k <= n, "Can't sample {} distinct items from {}", k, n);
+    let mut pool: Vec<usize> = (0..n).collect();
+    for i in 0..k {
+      let j = i + (self.generate() % (n - i) as u64) as usize;
+      pool.swap(i, j);
+    }
+    pool.truncate(k);
+    pool
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sample_distinct_returns_distinct_indices_in_range() {
+    let mut rng = Rng::new_from_seed(7);
+    for k in 0..=10 {
+      let mut sample = rng.sample_distinct(10, k);
+      assert_eq!(sample.len(), k);
+      assert!(sample.iter().all(|&i| i < 10));
+      sample.sort();
+      sample.dedup();
+      assert_eq!(sample.len(), k);
+    }
+  }
+
+  #[test]
+  fn sample_distinct_is_deterministic() {
+    let a = Rng::new_from_seed(3).sample_distinct(50, 5);
+    let b = Rng::new_from_seed(3).sample_distinct(50, 5);
+    assert_eq!(a, b);
+  }
+
+  #[test]
+  fn forks_are_deterministic_and_leave_the_parent_alone() {
+    let rng = Rng::new_from_seed(11);
+    let mut a = rng.fork(1);
+    let mut b = rng.fork(1);
+    let mut other = rng.fork(2);
+    let a: Vec<u64> = (0..4).map(|_| a.generate()).collect();
+    let b: Vec<u64> = (0..4).map(|_| b.generate()).collect();
+    let other: Vec<u64> = (0..4).map(|_| other.generate()).collect();
+    assert_eq!(a, b);
+    assert_ne!(a, other);
+    assert_eq!(rng.state(), 11);
+  }
+
+  #[test]
+  fn peek_shows_the_next_value_without_consuming_it() {
+    let mut rng = Rng::new_from_seed(5);
+    let peeked = rng.peek();
+    assert_eq!(rng.peek(), peeked);
+    assert_eq!(rng.generate(), peeked);
+    assert_ne!(rng.peek(), peeked);
+  }
 }
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use crate::game_state::{GameState, PlayerIndex, TerritoryIndex};
+
+/// Custom per-turn logic for campaign and scenario maps.
+pub trait ScenarioScript: std::fmt::Debug + Send + Sync {
+  /// Called at the start of every `step_time`, right after stale orders are cleared from empty
+  /// territories and before anything else happens, with the turn that's about to resolve.
+  fn on_turn(&mut self, state: &mut GameState, turn: u64);
+
+  /// Lets `GameState` stay `Clone` while holding a boxed script.
+  fn clone_box(&self) -> Box<dyn ScenarioScript>;
+}
+
+impl Clone for Box<dyn ScenarioScript> {
+  fn clone(&self) -> Self {
+    self.clone_box()
+  }
+}
+
+/// The built-in scripts a map file can carry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub enum MapScript {
+  /// On `turn`, gives `player` a stack of `units` on each of `territories` that's empty. Occupied
+  /// territories, and anything out of range, are skipped.
+  ScheduledSpawn {
+    turn:        u64,
+    player:      PlayerIndex,
+    territories: Vec<TerritoryIndex>,
+    units:       i32,
+  },
+}
+
+impl ScenarioScript for MapScript {
+  fn on_turn(&mut self, state: &mut GameState, turn: u64) {
+    match self {
+      MapScript::ScheduledSpawn {
+        turn: spawn_turn,
+        player,
+        territories,
+        units,
+      } => {
+        if turn != *spawn_turn || *player >= state.player_states.len() {
+          return;
+        }
+        for &terr_index in territories.iter() {
+          if let Some(terr) = state.territories.get_mut(terr_index) {
+            if terr.contents.is_none() {
+              terr.contents = Some((*player, *units));
+            }
+          }
+        }
+      }
+    }
+  }
+
+  fn clone_box(&self) -> Box<dyn ScenarioScript> {
+    Box::new(self.clone())
+  }
+}
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  game_state::{
+    GameEvent, GamePhase, GameState, PlayerIndex, PlayerState, Territory, TerritoryIndex,
+    WorldEvent,
+  },
+  rng::Rng,
+};
+
+/// What changed between two states of the same game, from `state_diff`.
+///
+/// Only what can change during play is covered: the config, the player tokens, and any scenario
+/// script are assumed to be the same on both sides.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ts_rs::TS)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+#[ts(export)]
+pub struct StateDiff {
+  pub turn:              u64,
+  pub phase:             GamePhase,
+  pub rng_state:         u64,
+  pub territory_count:   usize,
+  /// Every territory that differs, or that only exists in the new state.
+  pub territories:       Vec<(TerritoryIndex, Territory)>,
+  pub player_count:      usize,
+  /// Every player whose state differs, or who only exists in the new state.
+  pub player_states:     Vec<(PlayerIndex, PlayerState)>,
+  /// How many of the old state's game events the new state still has. Usually all of them, but a
+  /// rewind can drop some.
+  pub kept_game_events:  usize,
+  /// The game events after `kept_game_events`.
+  pub added_game_events: Vec<GameEvent>,
+  pub world_event:       Option<WorldEvent>,
+  pub recent_attacks:    Vec<(TerritoryIndex, PlayerIndex)>,
+  pub victory_streaks:   Vec<u64>,
+}
+
+/// Returns the changes that turn `old` into `new`. See `StateDiff::apply`.
+pub fn state_diff(old: &GameState, new: &GameState) -> StateDiff {
+  let territories = new
+    .territories
+    .iter()
+    .enumerate()
+    .filter(|&(i, terr)| old.territories.get(i) != Some(terr))
+    .map(|(i, terr)| (i, terr.clone()))
+    .collect();
+  let player_states = new
+    .player_states
+    .iter()
+    .enumerate()
+    .filter(|&(i, player)| old.player_states.get(i) != Some(player))
+    .map(|(i, player)| (i, player.clone()))
+    .collect();
+  let kept_game_events = old
+    .game_events
+    .iter()
+    .zip(&new.game_events)
+    .take_while(|(old_event, new_event)| old_event == new_event)
+    .count();
+  StateDiff {
+    turn: new.turn,
+    phase: new.phase,
+    rng_state: new.rng.state(),
+    territory_count: new.territories.len(),
+    territories,
+    player_count: new.player_states.len(),
+    player_states,
+    kept_game_events,
+    added_game_events: new.game_events[kept_game_events..].to_vec(),
+    world_event: new.world_event,
+    recent_attacks: new.recent_attacks.clone(),
+    victory_streaks: new.victory_streaks.clone(),
+  }
+}
+
+impl StateDiff {
+  /// Turns the `old` this diff was taken from into the `new` one.
+  pub fn apply(&self, state: &mut GameState) {
+    state.turn = self.turn;
+    state.phase = self.phase;
+    state.set_rng(Rng::new_from_seed(self.rng_state));
+    // Shrinking just drops the tail, and growing is filled in below, since every territory that
+    // only exists in the new state is part of the diff.
+    state.territories.truncate(self.territory_count);
+    for (i, terr) in &self.territories {
+      match state.territories.get_mut(*i) {
+        Some(existing) => *existing = terr.clone(),
+        None => state.territories.push(terr.clone()),
+      }
+    }
+    state.player_states.truncate(self.player_count);
+    for (i, player) in &self.player_states {
+      match state.player_states.get_mut(*i) {
+        Some(existing) => *existing = player.clone(),
+        None => state.player_states.push(player.clone()),
+      }
+    }
+    state.game_events.truncate(self.kept_game_events);
+    state.game_events.extend(self.added_game_events.iter().cloned());
+    state.world_event = self.world_event;
+    state.recent_attacks = self.recent_attacks.clone();
+    state.victory_streaks = self.victory_streaks.clone();
+    if !self.territories.is_empty() {
+      state.invalidate_distance_matrix();
+      state.invalidate_render_index();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    ai,
+    game_state::{GameAction, VictoryCondition},
+    map_gen::generate_hex_map,
+  };
+
+  /// Plays a few turns of AI against AI, returning the state before and after.
+  fn played_game() -> (GameState, GameState) {
+    let mut old = generate_hex_map(4, 2);
+    old.config.world_event_chance = 50;
+    // Everyone holds 1% of the board, so streaks build up without anyone winning.
+    old.config.victory_condition = VictoryCondition::Domination {
+      percent: 1,
+      turns:   100,
+    };
+    for player in 0..2 {
+      old.player_indices_by_token.insert(format!("player-{}", player), player);
+    }
+    let mut new = old.clone();
+    for _ in 0..3 {
+      for player in 0..2 {
+        for (territory, command) in ai::plan_commands(&new, player) {
+          let action = GameAction::SetCommand { territory, command };
+          new.process_action(&format!("player-{}", player), action).unwrap();
+        }
+      }
+      new.step_time().unwrap();
+    }
+    (old, new)
+  }
+
+  #[test]
+  fn applying_a_diff_reproduces_the_new_state() {
+    let (old, mut new) = played_game();
+    new.world_event = Some(WorldEvent::Storm);
+    new.recent_attacks.push((0, 1));
+    new.victory_streaks = vec![2, 0];
+    let diff = state_diff(&old, &new);
+    let mut applied = old.clone();
+    diff.apply(&mut applied);
+    assert_eq!(applied.state_hash(), new.state_hash());
+    assert_eq!(applied.territories, new.territories);
+    assert_eq!(applied.player_states, new.player_states);
+    assert_eq!(applied.game_events, new.game_events);
+    assert_eq!(applied.world_event, new.world_event);
+    assert_eq!(applied.recent_attacks, new.recent_attacks);
+    assert_eq!(applied.victory_streaks, new.victory_streaks);
+  }
+
+  #[test]
+  fn diffs_can_also_go_backwards() {
+    let (old, new) = played_game();
+    assert_ne!(new.state_hash(), old.state_hash());
+    assert_ne!(new.victory_streaks, old.victory_streaks);
+    let mut applied = new.clone();
+    state_diff(&new, &old).apply(&mut applied);
+    assert_eq!(applied.state_hash(), old.state_hash());
+    assert_eq!(applied.victory_streaks, old.victory_streaks);
+  }
+}